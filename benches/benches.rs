@@ -26,6 +26,7 @@ extern crate tikv;
 extern crate time;
 
 mod channel;
+mod worker;
 
 #[allow(dead_code)]
 #[path="../tests/util.rs"]
@@ -0,0 +1,46 @@
+// Copyright 2016 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use test::Bencher;
+
+use tikv::util::worker::{Runnable, Worker};
+
+const BATCH_SIZE: u64 = 100;
+
+struct NoopRunner;
+
+impl Runnable<u64> for NoopRunner {
+    fn run(&mut self, _: u64) {}
+}
+
+#[bench]
+fn bench_worker_schedule(b: &mut Bencher) {
+    let mut worker = Worker::new("bench-schedule");
+    worker.start(NoopRunner).unwrap();
+    b.iter(|| {
+        for i in 0..BATCH_SIZE {
+            worker.schedule(i).unwrap();
+        }
+    });
+    worker.stop().unwrap().join().unwrap();
+}
+
+#[bench]
+fn bench_worker_schedule_all(b: &mut Bencher) {
+    let mut worker = Worker::new("bench-schedule-all");
+    worker.start(NoopRunner).unwrap();
+    b.iter(|| {
+        worker.schedule_all((0..BATCH_SIZE).collect()).unwrap();
+    });
+    worker.stop().unwrap().join().unwrap();
+}
@@ -55,6 +55,7 @@ use tikv::server::{PdStoreAddrResolver, StoreAddrResolver};
 use tikv::raftstore::store::{self, SnapManager};
 use tikv::pd::RpcClient;
 use tikv::util::time_monitor::TimeMonitor;
+use tikv::util::worker::StallDetector;
 
 const ROCKSDB_STATS_KEY: &'static str = "rocksdb.stats";
 
@@ -663,5 +664,6 @@ fn main() {
         panic!("in raftkv, cluster_id must greater than 0");
     }
     let _m = TimeMonitor::default();
+    let _d = StallDetector::default();
     run_raft_server(listener, pd_client, &matches, &config, &cfg);
 }
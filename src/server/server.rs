@@ -25,7 +25,7 @@ use kvproto::msgpb::{MessageType, Message};
 use super::{Msg, ConnData};
 use super::conn::Conn;
 use super::{Result, OnResponse, Config};
-use util::worker::{Stopped, Worker};
+use util::worker::{ScheduleError, Worker};
 use util::transport::SendCh;
 use storage::Storage;
 use raftstore::store::SnapManager;
@@ -512,7 +512,7 @@ impl<T: RaftStoreRouter, S: StoreAddrResolver> Server<T, S> {
                 rep.report(SnapshotStatus::Finish);
             }
         };
-        if let Err(Stopped(SnapTask::SendTo { cb, .. })) = self.snap_worker
+        if let Err(ScheduleError::Stopped(SnapTask::SendTo { cb, .. })) = self.snap_worker
             .schedule(SnapTask::SendTo {
                 addr: sock_addr,
                 data: data,
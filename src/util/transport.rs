@@ -14,12 +14,14 @@
 
 use std::{thread, error};
 use std::fmt::Debug;
-use std::time::Duration;
+use std::cmp;
+use std::time::{Duration, Instant};
 use super::metrics::*;
 
 use mio::{Sender, NotifyError};
 
 const MAX_SEND_RETRY_CNT: usize = 5;
+const SEND_RETRY_INTERVAL_MILLIS: u64 = 100;
 
 quick_error! {
     #[derive(Debug)]
@@ -28,6 +30,9 @@ quick_error! {
             description("message is discarded")
             display("{}", reason)
         }
+        Closed {
+            description("channel is closed")
+        }
         Other(err: Box<error::Error + Send + Sync>) {
             from()
             cause(err.as_ref())
@@ -42,6 +47,7 @@ impl<T: Debug> From<NotifyError<T>> for Error {
         match e {
             // ALLERT!! May cause sensitive data leak.
             NotifyError::Full(m) => Error::Discard(format!("Failed to send {:?} due to full", m)),
+            NotifyError::Closed(_) => Error::Closed,
             _ => box_err!("{:?}", e),
         }
     }
@@ -65,10 +71,33 @@ impl<T: Debug> SendCh<T> {
         self.send_with_try_times(t, MAX_SEND_RETRY_CNT)
     }
 
+    /// Send t without retry, `Error::Discard` is returned if the channel is full
+    /// and `Error::Closed` if the receiver is gone.
     pub fn try_send(&self, t: T) -> Result<(), Error> {
         self.send_with_try_times(t, 1)
     }
 
+    /// Keep retrying to send t while the channel is full for at most `timeout`.
+    pub fn send_timeout(&self, mut t: T, timeout: Duration) -> Result<(), Error> {
+        let start = Instant::now();
+        loop {
+            t = match self.ch.send(t) {
+                Ok(_) => return Ok(()),
+                Err(NotifyError::Full(m)) => {
+                    let elapsed = start.elapsed();
+                    if elapsed >= timeout {
+                        CHANNEL_FULL_COUNTER_VEC.with_label_values(&[self.name]).inc();
+                        return Err(NotifyError::Full(m).into());
+                    }
+                    let interval = Duration::from_millis(SEND_RETRY_INTERVAL_MILLIS);
+                    thread::sleep(cmp::min(interval, timeout - elapsed));
+                    m
+                }
+                Err(e) => return Err(e.into()),
+            };
+        }
+    }
+
     fn send_with_try_times(&self, mut t: T, mut try_times: usize) -> Result<(), Error> {
         loop {
             t = match self.ch.send(t) {
@@ -86,7 +115,7 @@ impl<T: Debug> SendCh<T> {
 
             // ALLERT!! make cause sensitive data leak.
             warn!("notify queue is full, sleep and retry sending {:?}", t);
-            thread::sleep(Duration::from_millis(100));
+            thread::sleep(Duration::from_millis(SEND_RETRY_INTERVAL_MILLIS));
         }
     }
 }
@@ -170,4 +199,40 @@ mod tests {
 
         h.join().unwrap();
     }
+
+    #[test]
+    fn test_sendch_send_timeout() {
+        let mut builder = EventLoopBuilder::new();
+        builder.notify_capacity(2);
+        let mut event_loop = builder.build().unwrap();
+        let ch = SendCh::new(event_loop.channel(), "test");
+        let _ch = ch.clone();
+
+        ch.try_send(Msg::Sleep(0)).unwrap();
+        ch.try_send(Msg::Sleep(0)).unwrap();
+        match ch.send_timeout(Msg::Stop, Duration::from_millis(50)) {
+            Err(Error::Discard(_)) => {}
+            res => panic!("expect discard error, but found: {:?}", res),
+        }
+
+        let h = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            let mut sender = SenderHandler { ch: _ch };
+            event_loop.run(&mut sender).unwrap();
+        });
+        // succeeds once the event loop drains the channel.
+        ch.send_timeout(Msg::Stop, Duration::from_secs(3)).unwrap();
+        h.join().unwrap();
+    }
+
+    #[test]
+    fn test_sendch_closed() {
+        let event_loop: EventLoop<SenderHandler> = EventLoop::new().unwrap();
+        let ch = SendCh::new(event_loop.channel(), "test");
+        drop(event_loop);
+        match ch.try_send(Msg::Stop) {
+            Err(Error::Closed) => {}
+            res => panic!("expect closed error, but found: {:?}", res),
+        }
+    }
 }
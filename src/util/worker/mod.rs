@@ -1,38 +1,209 @@
 /// Worker contains all workers that do the expensive job in background.
 
 
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Weak, Mutex};
 use std::thread::{self, JoinHandle, Builder};
 use std::io;
+use std::cmp;
+use std::usize;
+use std::cell::Cell;
+use std::collections::VecDeque;
 use std::fmt::{self, Formatter, Display, Debug};
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::mpsc::{self, Sender, Receiver, SendError};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::sync::mpsc::{self, Sender, Receiver, SendError, RecvTimeoutError};
 use std::error::Error;
+use std::time::{Duration, Instant};
 
-use util::SlowTimer;
+use util::{SlowTimer, duration_to_ms};
 
-pub struct Stopped<T>(pub T);
+mod stall;
+mod timer;
+mod retry;
+pub mod test_util;
 
-impl<T> Display for Stopped<T> {
+pub use self::stall::{StallDetector, StallKind};
+pub use self::timer::{TimerWorker, TimerHandle, TimerToken};
+pub use self::retry::{Retryable, RetryError, RetryableRunnable};
+
+/// The error of scheduling a task, the task is returned back.
+pub enum ScheduleError<T> {
+    /// The worker is stopped.
+    Stopped(T),
+    /// The pending tasks of the task's class reach its limit, see
+    /// `Worker::set_class_limits`.
+    ClassFull(T),
+}
+
+impl<T> ScheduleError<T> {
+    pub fn into_inner(self) -> T {
+        match self {
+            ScheduleError::Stopped(t) |
+            ScheduleError::ClassFull(t) => t,
+        }
+    }
+}
+
+impl<T> Display for ScheduleError<T> {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        write!(f, "channel has been closed")
+        match *self {
+            ScheduleError::Stopped(_) => write!(f, "channel has been closed"),
+            ScheduleError::ClassFull(_) => write!(f, "too many pending tasks of the class"),
+        }
     }
 }
 
-impl<T> Debug for Stopped<T> {
+impl<T> Debug for ScheduleError<T> {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        write!(f, "channel has been closed")
+        Display::fmt(self, f)
     }
 }
 
-impl<T> From<Stopped<T>> for Box<Error + Sync + Send + 'static> {
-    fn from(_: Stopped<T>) -> Box<Error + Sync + Send + 'static> {
-        box_err!("channel has been closed")
+impl<T> From<ScheduleError<T>> for Box<Error + Sync + Send + 'static> {
+    fn from(e: ScheduleError<T>) -> Box<Error + Sync + Send + 'static> {
+        box_err!(format!("{}", e))
     }
 }
 
+/// Assigns tasks to classes, so that every class can have its own limit of
+/// pending tasks.
+pub trait TaskClass {
+    /// The class of the task, must be less than `class_count`.
+    fn class(&self) -> usize;
+
+    fn class_count() -> usize;
+}
+
 pub trait Runnable<T: Display> {
     fn run(&mut self, t: T);
+
+    /// Called in the worker thread before handling any task, with the scheduler
+    /// of the worker, so that the runner can schedule follow-up tasks to itself.
+    fn on_start(&mut self, _: Scheduler<T>) {}
+
+    /// How long to wait for new tasks before `on_timeout` is called.
+    ///
+    /// It's asked every time the worker is going to wait, None means waiting forever.
+    fn timeout(&mut self) -> Option<Duration> {
+        None
+    }
+
+    /// Called when no task arrives within `timeout`.
+    fn on_timeout(&mut self) {}
+}
+
+pub trait ControlledBatchRunnable<T: Display> {
+    /// run a batch of tasks.
+    ///
+    /// Different from `BatchRunnable`, tasks left in ts are not dropped, but
+    /// handled again at the front of next batch. Tasks must not be added to ts.
+    ///
+    /// If none of the tasks is handled, next batch is delayed for a while. Once
+    /// the worker is stopping, such leftovers are dropped and counted as rejected.
+    fn run_batch(&mut self, ts: &mut Vec<T>, meta: &BatchMeta);
+
+    /// See `Runnable::on_start`.
+    fn on_start(&mut self, _: Scheduler<T>) {}
+}
+
+/// What the threads of a worker drive.
+trait Handler<T> {
+    fn on_start(&mut self, scheduler: Scheduler<T>);
+
+    fn handle(&mut self, ts: &mut Vec<T>, meta: &BatchMeta);
+
+    fn timeout(&mut self) -> Option<Duration>;
+
+    fn on_timeout(&mut self);
+
+    /// Whether the tasks left in ts should be handled in next batch.
+    fn keep_leftovers(&self) -> bool;
+}
+
+struct Plain<R>(R);
+
+impl<T: Display, R: BatchRunnable<T>> Handler<T> for Plain<R> {
+    fn on_start(&mut self, scheduler: Scheduler<T>) {
+        self.0.on_start(scheduler)
+    }
+
+    fn handle(&mut self, ts: &mut Vec<T>, meta: &BatchMeta) {
+        self.0.run_batch_with_meta(ts, meta)
+    }
+
+    fn timeout(&mut self) -> Option<Duration> {
+        self.0.timeout()
+    }
+
+    fn on_timeout(&mut self) {
+        self.0.on_timeout()
+    }
+
+    fn keep_leftovers(&self) -> bool {
+        false
+    }
+}
+
+struct Controlled<R>(R);
+
+impl<T: Display, R: ControlledBatchRunnable<T>> Handler<T> for Controlled<R> {
+    fn on_start(&mut self, scheduler: Scheduler<T>) {
+        self.0.on_start(scheduler)
+    }
+
+    fn handle(&mut self, ts: &mut Vec<T>, meta: &BatchMeta) {
+        self.0.run_batch(ts, meta)
+    }
+
+    fn timeout(&mut self) -> Option<Duration> {
+        None
+    }
+
+    fn on_timeout(&mut self) {}
+
+    fn keep_leftovers(&self) -> bool {
+        true
+    }
+}
+
+/// Information about the batch being handled.
+#[derive(Debug, Clone, Copy)]
+pub struct BatchMeta {
+    /// When the oldest task in the batch was scheduled.
+    pub oldest_enqueued_at: Instant,
+    /// How long the oldest task in the batch waited in the queue.
+    pub wait: Duration,
+    /// The sequence number of the batch in the worker, starts from 1.
+    pub batch_seq: u64,
+    /// The trace ID shared by all the tasks in the batch, None if any of them
+    /// is not traced or they belong to different traces.
+    pub trace_id: Option<u64>,
+}
+
+thread_local! {
+    static CURRENT_TRACE_ID: Cell<Option<u64>> = Cell::new(None)
+}
+
+/// Get the trace ID of the batch being handled by current thread, see
+/// `BatchMeta::trace_id`. It's always None for a batch that mixes traces, so
+/// a task never picks up the trace of another one.
+///
+/// Runners can pass it to `Scheduler::schedule_traced` to keep the follow-up
+/// tasks in the same trace.
+pub fn current_trace_id() -> Option<u64> {
+    CURRENT_TRACE_ID.with(|id| id.get())
+}
+
+/// Formats as ` [trace=id]` for traced tasks, and nothing for the others.
+struct Trace(Option<u64>);
+
+impl Display for Trace {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.0 {
+            Some(id) => write!(f, " [trace={}]", id),
+            None => Ok(()),
+        }
+    }
 }
 
 pub trait BatchRunnable<T: Display> {
@@ -40,29 +211,385 @@ pub trait BatchRunnable<T: Display> {
     ///
     /// Please note that ts will be clear after invoking this method.
     fn run_batch(&mut self, ts: &mut Vec<T>);
+
+    /// run a batch of tasks with the information about the batch.
+    ///
+    /// The worker always calls this method, by default it just calls `run_batch`.
+    fn run_batch_with_meta(&mut self, ts: &mut Vec<T>, _: &BatchMeta) {
+        self.run_batch(ts)
+    }
+
+    /// See `Runnable::timeout`.
+    fn timeout(&mut self) -> Option<Duration> {
+        None
+    }
+
+    /// See `Runnable::on_timeout`.
+    fn on_timeout(&mut self) {}
+
+    /// See `Runnable::on_start`.
+    fn on_start(&mut self, _: Scheduler<T>) {}
 }
 
 impl<T: Display, R: Runnable<T>> BatchRunnable<T> for R {
     fn run_batch(&mut self, ts: &mut Vec<T>) {
         for t in ts.drain(..) {
-            let task_str = format!("{}", t);
-            let timer = SlowTimer::new();
             self.run(t);
-            slow_log!(timer, "handle task {}", task_str);
         }
     }
+
+    fn timeout(&mut self) -> Option<Duration> {
+        Runnable::timeout(self)
+    }
+
+    fn on_timeout(&mut self) {
+        Runnable::on_timeout(self)
+    }
+
+    fn on_start(&mut self, scheduler: Scheduler<T>) {
+        Runnable::on_start(self, scheduler)
+    }
+}
+
+/// A task with the sequence number assigned when it was scheduled.
+struct Envelope<T> {
+    seq: u64,
+    lane: usize,
+    class: Option<usize>,
+    enqueued_at: Instant,
+    trace_id: Option<u64>,
+    task: T,
+}
+
+/// Identifies the tasks of a batch in logs and `Worker::current_task`.
+///
+/// Tasks are only formatted for workers started with a `Runnable`, the
+/// batches of other workers are identified by sequence numbers, so they
+/// don't pay for formatting every task.
+#[derive(Clone)]
+struct BatchLabel {
+    format_tasks: bool,
+    // the formatted tasks, empty unless `format_tasks`.
+    tasks: Vec<String>,
+    // the sequence number and trace ID of every task.
+    seqs: Vec<(u64, Option<u64>)>,
+    // the count of tasks carried over from last batch.
+    leftovers: usize,
+}
+
+impl BatchLabel {
+    fn new(format_tasks: bool) -> BatchLabel {
+        BatchLabel {
+            format_tasks: format_tasks,
+            tasks: vec![],
+            seqs: vec![],
+            leftovers: 0,
+        }
+    }
+
+    fn push<T: Display>(&mut self, e: &Envelope<T>) {
+        if self.format_tasks {
+            self.tasks.push(format!("{}", e.task));
+        }
+        self.seqs.push((e.seq, e.trace_id));
+    }
+
+    fn clear(&mut self) {
+        self.tasks.clear();
+        self.seqs.clear();
+        self.leftovers = 0;
+    }
+}
+
+impl Display for BatchLabel {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let mut sep = "";
+        if self.leftovers > 0 {
+            try!(write!(f, "{} leftover tasks", self.leftovers));
+            sep = ", ";
+        }
+        for (i, &(seq, trace_id)) in self.seqs.iter().enumerate() {
+            try!(write!(f, "{}", sep));
+            if let Some(task) = self.tasks.get(i) {
+                try!(write!(f, "{} ", task));
+            }
+            try!(write!(f, "[seq={}]{}", seq, Trace(trace_id)));
+            sep = ", ";
+        }
+        Ok(())
+    }
+}
+
+/// Counters shared between a worker and all its schedulers.
+struct Shared {
+    // unique among all the workers created in the process, addresses of dropped
+    // workers may be reused so they can't tell workers apart.
+    id: usize,
+    name: String,
+    pending: AtomicUsize,
+    // pending tasks of every lane, their sum plus `leftovers` equals to `pending`.
+    lane_pending: Vec<AtomicUsize>,
+    // tasks left by controlled batch runners, they are pending again but no
+    // longer belong to any lane or class.
+    leftovers: AtomicUsize,
+    // the last sequence number assigned by `schedule`.
+    seq: AtomicUsize,
+    // the largest sequence number of the tasks handed to the runner.
+    dispatched_seq: AtomicUsize,
+    // the count of tasks that have been handled.
+    handled: AtomicUsize,
+    // the count of batches that have been dispatched.
+    batches: AtomicUsize,
+    // the count of threads, 0 if the worker is not started yet.
+    threads: AtomicUsize,
+    // the count of threads that have not exited yet.
+    running: AtomicUsize,
+    // set when the worker is asked to stop, threads should never exit before.
+    stopped: AtomicBool,
+    // labels of the tasks being handled by every thread.
+    current: Mutex<Vec<Option<BatchLabel>>>,
+    // the worker becomes busy when pending reaches `busy_high`, and becomes
+    // idle again only after pending drops to `busy_low`.
+    busy_high: AtomicUsize,
+    busy_low: AtomicUsize,
+    busy: AtomicBool,
+    // the count of tasks failed to be scheduled because the worker is stopped,
+    // or dropped by the rate limit when the worker stops.
+    rejected: AtomicUsize,
+    // pending tasks and the limit of every class, empty if tasks are not classified.
+    class_pending: Vec<AtomicUsize>,
+    class_limits: Vec<usize>,
+    created_at: Instant,
+    // milliseconds from `created_at` to the last time a thread of the worker
+    // woke up, plus 1; 0 means the worker is not started yet.
+    last_active: AtomicUsize,
+    // log one of every `log_sample_interval` tasks at info level, 0 means never.
+    log_sample_interval: AtomicUsize,
+    // the count of sampled logs, only for test.
+    #[cfg(test)]
+    sampled_logs: AtomicUsize,
+}
+
+impl Shared {
+    fn new(name: String, lanes: usize) -> Shared {
+        Shared {
+            id: NEXT_WORKER_ID.fetch_add(1, Ordering::SeqCst),
+            name: name,
+            pending: AtomicUsize::new(0),
+            lane_pending: (0..lanes).map(|_| AtomicUsize::new(0)).collect(),
+            leftovers: AtomicUsize::new(0),
+            seq: AtomicUsize::new(0),
+            dispatched_seq: AtomicUsize::new(0),
+            handled: AtomicUsize::new(0),
+            batches: AtomicUsize::new(0),
+            threads: AtomicUsize::new(0),
+            running: AtomicUsize::new(0),
+            stopped: AtomicBool::new(false),
+            current: Mutex::new(vec![]),
+            busy_high: AtomicUsize::new(1),
+            busy_low: AtomicUsize::new(0),
+            busy: AtomicBool::new(false),
+            rejected: AtomicUsize::new(0),
+            class_pending: vec![],
+            class_limits: vec![],
+            created_at: Instant::now(),
+            last_active: AtomicUsize::new(0),
+            log_sample_interval: AtomicUsize::new(0),
+            #[cfg(test)]
+            sampled_logs: AtomicUsize::new(0),
+        }
+    }
+
+    /// Check if the count of tasks crosses a multiple of the sampling interval
+    /// when it grows from `before` to `after`.
+    fn sampled(&self, before: usize, after: usize) -> bool {
+        let interval = self.log_sample_interval.load(Ordering::Relaxed);
+        if interval == 0 || before / interval == after / interval {
+            return false;
+        }
+        self.on_sampled();
+        true
+    }
+
+    #[cfg(test)]
+    fn on_sampled(&self) {
+        self.sampled_logs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(not(test))]
+    fn on_sampled(&self) {}
+
+    fn touch(&self) {
+        let elapsed = duration_to_ms(self.created_at.elapsed()) as usize;
+        self.last_active.store(elapsed + 1, Ordering::SeqCst);
+    }
+
+    fn last_active(&self) -> Option<Instant> {
+        match self.last_active.load(Ordering::SeqCst) {
+            0 => None,
+            ms => Some(self.created_at + Duration::from_millis(ms as u64 - 1)),
+        }
+    }
+
+    fn on_rejected(&self, count: usize) {
+        let prev = self.rejected.fetch_add(count, Ordering::SeqCst);
+        let rejected = prev + count;
+        // only warn when the count doubles, so it won't flood the log.
+        if (prev + 1).next_power_of_two() <= rejected {
+            warn!("worker {} is stopped, {} tasks have been rejected",
+                  self.name,
+                  rejected);
+        }
+    }
+
+    /// Update the busy state according to the current pending count.
+    ///
+    /// Schedulers and worker threads update the state concurrently, so a
+    /// thread may store a state derived from a stale count after another one
+    /// stores the fresh state. Check the count again after storing, and retry
+    /// until the state agrees with it.
+    fn update_busy(&self) {
+        loop {
+            let pending = self.pending.load(Ordering::SeqCst);
+            if pending >= self.busy_high.load(Ordering::SeqCst) {
+                self.busy.store(true, Ordering::SeqCst);
+            } else if pending <= self.busy_low.load(Ordering::SeqCst) {
+                self.busy.store(false, Ordering::SeqCst);
+            } else {
+                return;
+            }
+            if self.pending.load(Ordering::SeqCst) == pending {
+                return;
+            }
+        }
+    }
+
+    /// Record that the task `seq` is handed to the runner.
+    ///
+    /// Tasks are not dispatched in the order of sequence numbers, as producers
+    /// race to send them and lanes are drained by their own policy, so only
+    /// move it forward.
+    fn on_dispatched(&self, seq: usize) {
+        let mut current = self.dispatched_seq.load(Ordering::SeqCst);
+        while current < seq {
+            let prev = self.dispatched_seq.compare_and_swap(current, seq, Ordering::SeqCst);
+            if prev == current {
+                return;
+            }
+            current = prev;
+        }
+    }
+
+    fn set_threads(&self, count: usize) {
+        self.threads.store(count, Ordering::SeqCst);
+        self.running.store(count, Ordering::SeqCst);
+        *self.current.lock().unwrap() = vec![None; count];
+    }
+
+    fn current_task(&self) -> Option<String> {
+        let current = self.current.lock().unwrap();
+        let labels: Vec<_> = current.iter()
+            .filter_map(|l| l.as_ref())
+            .map(|l| l.to_string())
+            .collect();
+        if labels.is_empty() {
+            None
+        } else {
+            Some(labels.join(", "))
+        }
+    }
+}
+
+/// A snapshot of the worker statistics.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorkerStats {
+    pub pending: usize,
+    pub lane_pending: Vec<usize>,
+    /// Tasks carried to the next batch by a `ControlledBatchRunnable`, they are
+    /// counted in `pending` but not in `lane_pending` or `class_pending`.
+    pub leftovers: usize,
+    pub last_scheduled_seq: u64,
+    pub last_dispatched_seq: u64,
+    pub handled: usize,
+    pub threads: usize,
+    pub current_task: Option<String>,
+    pub rejected: usize,
+    pub class_pending: Vec<usize>,
+    pub last_active: Option<Instant>,
+}
+
+impl WorkerStats {
+    /// Get how many scheduled tasks are not handed to the runner yet.
+    ///
+    /// It can't be derived from the sequence numbers, as a task scheduled later
+    /// may be dispatched before the earlier ones, e.g. by a higher priority lane.
+    pub fn backlog(&self) -> u64 {
+        self.pending as u64
+    }
+}
+
+static NEXT_WORKER_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+lazy_static! {
+    // started workers that are checked by `StallDetector`.
+    static ref REGISTRY: Mutex<Vec<(String, Weak<Shared>)>> = Mutex::new(vec![]);
+}
+
+fn register(name: &str, shared: &Arc<Shared>) {
+    let mut registry = REGISTRY.lock().unwrap();
+    // forget the workers that have been dropped.
+    registry.retain(|&(_, ref s)| s.upgrade().is_some());
+    registry.push((name.to_owned(), Arc::downgrade(shared)));
+}
+
+/// The progress of a registered worker.
+struct Progress {
+    id: usize,
+    name: String,
+    pending: usize,
+    handled: usize,
+    threads: usize,
+    running: usize,
+    stopped: bool,
+    current_task: Option<String>,
+    last_active: Option<Instant>,
+}
+
+fn registered_progress() -> Vec<Progress> {
+    let registry = REGISTRY.lock().unwrap();
+    registry.iter()
+        .filter_map(|&(ref name, ref s)| {
+            s.upgrade().map(|s| {
+                Progress {
+                    id: s.id,
+                    name: name.clone(),
+                    pending: s.pending.load(Ordering::SeqCst),
+                    handled: s.handled.load(Ordering::SeqCst),
+                    threads: s.threads.load(Ordering::SeqCst),
+                    running: s.running.load(Ordering::SeqCst),
+                    stopped: s.stopped.load(Ordering::SeqCst),
+                    current_task: s.current_task(),
+                    last_active: s.last_active(),
+                }
+            })
+        })
+        .collect()
 }
 
 /// Scheduler provides interface to schedule task to underlying workers.
 pub struct Scheduler<T> {
-    counter: Arc<AtomicUsize>,
-    sender: Sender<Option<T>>,
+    lane: usize,
+    classify: Option<fn(&T) -> usize>,
+    shared: Arc<Shared>,
+    sender: Sender<Option<Envelope<T>>>,
 }
 
 impl<T: Display> Scheduler<T> {
-    fn new(counter: AtomicUsize, sender: Sender<Option<T>>) -> Scheduler<T> {
+    fn new(lane: usize, shared: Arc<Shared>, sender: Sender<Option<Envelope<T>>>) -> Scheduler<T> {
         Scheduler {
-            counter: Arc::new(counter),
+            lane: lane,
+            classify: None,
+            shared: shared,
             sender: sender,
         }
     }
@@ -70,25 +597,193 @@ impl<T: Display> Scheduler<T> {
     /// Schedule a task to run.
     ///
     /// If the worker is stopped, an error will return.
-    pub fn schedule(&self, task: T) -> Result<(), Stopped<T>> {
-        debug!("scheduling task {}", task);
-        if let Err(SendError(Some(t))) = self.sender.send(Some(task)) {
-            return Err(Stopped(t));
+    pub fn schedule(&self, task: T) -> Result<(), ScheduleError<T>> {
+        self.schedule_seq(task).map(|_| ())
+    }
+
+    /// Schedule a task to run and return the sequence number assigned to it.
+    ///
+    /// Sequence numbers are shared by all the schedulers of the same worker, they
+    /// start from 1 and are strictly increasing.
+    pub fn schedule_seq(&self, task: T) -> Result<u64, ScheduleError<T>> {
+        self.send(task, None)
+    }
+
+    /// Schedule a task that belongs to the trace `trace_id`.
+    ///
+    /// The trace ID is shown in the logs of the task, and can be fetched by
+    /// `current_trace_id` when the task is being handled.
+    pub fn schedule_traced(&self, task: T, trace_id: u64) -> Result<(), ScheduleError<T>> {
+        self.send(task, Some(trace_id)).map(|_| ())
+    }
+
+    fn send(&self, task: T, trace_id: Option<u64>) -> Result<u64, ScheduleError<T>> {
+        let class = self.classify.map(|classify| classify(&task));
+        if let Some(c) = class {
+            let pending = self.shared.class_pending[c].fetch_add(1, Ordering::SeqCst);
+            if pending >= self.shared.class_limits[c] {
+                self.shared.class_pending[c].fetch_sub(1, Ordering::SeqCst);
+                return Err(ScheduleError::ClassFull(task));
+            }
+        }
+        let seq = self.shared.seq.fetch_add(1, Ordering::SeqCst) as u64 + 1;
+        debug!("scheduling task {} [seq={}]{}", task, seq, Trace(trace_id));
+        let sampled = if self.shared.sampled(seq as usize - 1, seq as usize) {
+            Some(format!("{} [seq={}]{}", task, seq, Trace(trace_id)))
+        } else {
+            None
+        };
+        let envelope = Envelope {
+            seq: seq,
+            lane: self.lane,
+            class: class,
+            enqueued_at: Instant::now(),
+            trace_id: trace_id,
+            task: task,
+        };
+        // count it before sending, so the worker never sees a task that is not counted.
+        self.shared.lane_pending[self.lane].fetch_add(1, Ordering::SeqCst);
+        let pending = self.shared.pending.fetch_add(1, Ordering::SeqCst) + 1;
+        if let Err(SendError(Some(e))) = self.sender.send(Some(envelope)) {
+            self.shared.lane_pending[self.lane].fetch_sub(1, Ordering::SeqCst);
+            self.shared.pending.fetch_sub(1, Ordering::SeqCst);
+            if let Some(c) = class {
+                self.shared.class_pending[c].fetch_sub(1, Ordering::SeqCst);
+            }
+            self.shared.update_busy();
+            self.shared.on_rejected(1);
+            return Err(ScheduleError::Stopped(e.task));
+        }
+        self.shared.update_busy();
+        if let Some(label) = sampled {
+            info!("{} scheduling task {} [pending {}]",
+                  self.shared.name,
+                  label,
+                  pending);
+        }
+        Ok(seq)
+    }
+
+    /// Schedule all the tasks in order, counters are updated once for all.
+    ///
+    /// If any class can't hold its tasks in the batch, the whole batch is
+    /// rejected with `ClassFull`. If the worker is stopped midway, the tasks
+    /// that are not sent are returned with `Stopped`.
+    pub fn schedule_all(&self, tasks: Vec<T>) -> Result<(), ScheduleError<Vec<T>>> {
+        let n = tasks.len();
+        if n == 0 {
+            return Ok(());
+        }
+        let classes: Vec<_> = match self.classify {
+            Some(classify) => tasks.iter().map(classify).collect(),
+            None => vec![],
+        };
+        if !classes.is_empty() {
+            let mut counts = vec![0; self.shared.class_limits.len()];
+            for &c in &classes {
+                counts[c] += 1;
+            }
+            for (c, &count) in counts.iter().enumerate() {
+                let pending = self.shared.class_pending[c].fetch_add(count, Ordering::SeqCst);
+                if pending + count > self.shared.class_limits[c] {
+                    for (c, &count) in counts.iter().enumerate().take(c + 1) {
+                        self.shared.class_pending[c].fetch_sub(count, Ordering::SeqCst);
+                    }
+                    return Err(ScheduleError::ClassFull(tasks));
+                }
+            }
+        }
+        let base = self.shared.seq.fetch_add(n, Ordering::SeqCst) as u64;
+        debug!("scheduling {} tasks [seq={}..{}]", n, base + 1, base + n as u64);
+        self.shared.lane_pending[self.lane].fetch_add(n, Ordering::SeqCst);
+        let pending = self.shared.pending.fetch_add(n, Ordering::SeqCst) + n;
+        let now = Instant::now();
+        let mut tasks = tasks.into_iter();
+        let mut sent = 0;
+        while let Some(task) = tasks.next() {
+            let envelope = Envelope {
+                seq: base + sent as u64 + 1,
+                lane: self.lane,
+                class: classes.get(sent).cloned(),
+                enqueued_at: now,
+                trace_id: None,
+                task: task,
+            };
+            if let Err(SendError(Some(e))) = self.sender.send(Some(envelope)) {
+                let mut unsent = Vec::with_capacity(n - sent);
+                unsent.push(e.task);
+                unsent.extend(tasks);
+                let count = unsent.len();
+                self.shared.lane_pending[self.lane].fetch_sub(count, Ordering::SeqCst);
+                self.shared.pending.fetch_sub(count, Ordering::SeqCst);
+                for &c in classes.iter().skip(sent) {
+                    self.shared.class_pending[c].fetch_sub(1, Ordering::SeqCst);
+                }
+                self.shared.update_busy();
+                self.shared.on_rejected(count);
+                return Err(ScheduleError::Stopped(unsent));
+            }
+            sent += 1;
+        }
+        self.shared.update_busy();
+        if self.shared.sampled(base as usize, base as usize + n) {
+            info!("{} scheduling {} tasks [seq={}..{}] [pending {}]",
+                  self.shared.name,
+                  n,
+                  base + 1,
+                  base + n as u64,
+                  pending);
         }
-        self.counter.fetch_add(1, Ordering::SeqCst);
         Ok(())
     }
 
     /// Check if underlying worker can't handle task immediately.
+    ///
+    /// See `Worker::set_busy_watermarks` for how the state changes.
     pub fn is_busy(&self) -> bool {
-        self.counter.load(Ordering::SeqCst) > 0
+        self.shared.busy.load(Ordering::SeqCst)
+    }
+
+    /// Get the count of tasks rejected because the worker is stopped, including
+    /// the throttled tasks dropped when it stops, see `set_max_tasks_per_sec`.
+    ///
+    /// It's shared by all the schedulers of the same worker.
+    pub fn rejected_count(&self) -> usize {
+        self.shared.rejected.load(Ordering::SeqCst)
+    }
+
+    /// Get the statistics of the underlying worker.
+    pub fn stats(&self) -> WorkerStats {
+        WorkerStats {
+            pending: self.shared.pending.load(Ordering::SeqCst),
+            lane_pending: self.shared
+                .lane_pending
+                .iter()
+                .map(|c| c.load(Ordering::SeqCst))
+                .collect(),
+            leftovers: self.shared.leftovers.load(Ordering::SeqCst),
+            last_scheduled_seq: self.shared.seq.load(Ordering::SeqCst) as u64,
+            last_dispatched_seq: self.shared.dispatched_seq.load(Ordering::SeqCst) as u64,
+            handled: self.shared.handled.load(Ordering::SeqCst),
+            threads: self.shared.threads.load(Ordering::SeqCst),
+            current_task: self.shared.current_task(),
+            rejected: self.rejected_count(),
+            class_pending: self.shared
+                .class_pending
+                .iter()
+                .map(|c| c.load(Ordering::SeqCst))
+                .collect(),
+            last_active: self.shared.last_active(),
+        }
     }
 }
 
 impl<T: Display> Clone for Scheduler<T> {
     fn clone(&self) -> Scheduler<T> {
         Scheduler {
-            counter: self.counter.clone(),
+            lane: self.lane,
+            classify: self.classify,
+            shared: self.shared.clone(),
             sender: self.sender.clone(),
         }
     }
@@ -100,125 +795,926 @@ impl<T: Display> Clone for Scheduler<T> {
 #[cfg(test)]
 pub fn dummy_scheduler<T: Display>() -> Scheduler<T> {
     let (tx, _) = mpsc::channel();
-    Scheduler::new(AtomicUsize::new(0), tx)
+    Scheduler::new(0, Arc::new(Shared::new("dummy".to_owned(), 1)), tx)
 }
 
 /// A worker that can schedule time consuming tasks.
 pub struct Worker<T: Display> {
     name: String,
     scheduler: Scheduler<T>,
-    receiver: Mutex<Option<Receiver<Option<T>>>>,
+    policy: Policy,
+    detect_stall: bool,
+    receiver: Mutex<Option<Receiver<Option<Envelope<T>>>>>,
     handle: Option<JoinHandle<()>>,
+    heartbeat: Option<Duration>,
+    // the rate and burst of dispatching tasks, None means unlimited.
+    rate_limit: Option<(f64, usize)>,
 }
 
-fn poll<R, T>(mut runner: R, rx: Receiver<Option<T>>, counter: Arc<AtomicUsize>, batch_size: usize)
-    where R: BatchRunnable<T> + Send + 'static,
-          T: Display + Send + 'static
-{
-    let mut keep_going = true;
-    let mut buffer = Vec::with_capacity(batch_size);
-    while keep_going {
-        let t = rx.recv();
-        match t {
-            Ok(Some(t)) => buffer.push(t),
-            _ => return,
-        }
-        while buffer.len() < batch_size {
-            match rx.try_recv() {
-                Ok(None) => {
-                    keep_going = false;
-                    break;
-                }
-                Ok(Some(t)) => buffer.push(t),
-                _ => break,
-            }
-        }
-        counter.fetch_sub(buffer.len(), Ordering::SeqCst);
-        runner.run_batch(&mut buffer);
-        buffer.clear();
-    }
+const DEFAULT_AGING_LIMIT: usize = 32;
+const DEFAULT_HEARTBEAT_INTERVAL_SECS: u64 = 1;
+
+/// Policy to choose the lane to dispatch task from.
+#[derive(Clone)]
+enum Policy {
+    /// When several lanes have tasks, lane i gets `weights[i]` tasks dispatched
+    /// in every round; lanes without tasks don't block the others.
+    Weighted(Vec<usize>),
+    /// Lane with smaller index has higher priority, but a lane that has been
+    /// skipped for `aging_limit` times will be picked anyway.
+    Priority { lanes: usize, aging_limit: usize },
 }
 
-impl<T: Display + Send + 'static> Worker<T> {
-    /// Create a worker.
-    pub fn new<S: Into<String>>(name: S) -> Worker<T> {
-        let (tx, rx) = mpsc::channel();
-        Worker {
-            name: name.into(),
-            scheduler: Scheduler::new(AtomicUsize::new(0), tx),
-            receiver: Mutex::new(Some(rx)),
-            handle: None,
+impl Policy {
+    fn lanes(&self) -> usize {
+        match *self {
+            Policy::Weighted(ref weights) => weights.len(),
+            Policy::Priority { lanes, .. } => lanes,
         }
     }
+}
 
-    /// Start the worker.
-    pub fn start<R: Runnable<T> + Send + 'static>(&mut self, runner: R) -> Result<(), io::Error> {
-        self.start_batch(runner, 1)
-    }
+/// Tasks received but not handed to the runner yet, grouped by lane.
+struct Lanes<T> {
+    queues: Vec<VecDeque<Envelope<T>>>,
+    policy: Policy,
+    // remaining tasks every lane can dispatch in current round, used by weighted policy.
+    credits: Vec<usize>,
+    cursor: usize,
+    // times every lane has been skipped, used by priority policy.
+    skipped: Vec<usize>,
+    len: usize,
+}
 
-    pub fn start_batch<R>(&mut self, runner: R, batch_size: usize) -> Result<(), io::Error>
-        where R: BatchRunnable<T> + Send + 'static
-    {
-        let mut receiver = self.receiver.lock().unwrap();
-        info!("starting working thread: {}", self.name);
-        if receiver.is_none() {
-            warn!("worker {} has been started.", self.name);
-            return Ok(());
+impl<T> Lanes<T> {
+    fn new(policy: Policy) -> Lanes<T> {
+        let lanes = policy.lanes();
+        Lanes {
+            queues: (0..lanes).map(|_| VecDeque::new()).collect(),
+            credits: match policy {
+                Policy::Weighted(ref weights) => weights.clone(),
+                Policy::Priority { .. } => vec![],
+            },
+            policy: policy,
+            cursor: 0,
+            skipped: vec![0; lanes],
+            len: 0,
         }
-
-        let rx = receiver.take().unwrap();
-        let counter = self.scheduler.counter.clone();
-        let h = try!(Builder::new()
-            .name(thd_name!(self.name.clone()))
-            .spawn(move || poll(runner, rx, counter, batch_size)));
-        self.handle = Some(h);
-        Ok(())
     }
 
-    /// Get a scheduler to schedule task.
-    pub fn scheduler(&self) -> Scheduler<T> {
-        self.scheduler.clone()
+    fn is_empty(&self) -> bool {
+        self.len == 0
     }
 
-    /// Schedule a task to run.
-    ///
-    /// If the worker is stopped, an error will return.
-    pub fn schedule(&self, task: T) -> Result<(), Stopped<T>> {
-        self.scheduler.schedule(task)
+    fn push(&mut self, e: Envelope<T>) {
+        self.queues[e.lane].push_back(e);
+        self.len += 1;
     }
 
-    /// Check if underlying worker can't handle task immediately.
-    pub fn is_busy(&self) -> bool {
-        self.handle.is_none() || self.scheduler.is_busy()
+    fn pop(&mut self) -> Option<Envelope<T>> {
+        if self.len == 0 {
+            return None;
+        }
+        let i = match self.policy {
+            Policy::Weighted(_) => self.pick_weighted(),
+            Policy::Priority { aging_limit, .. } => self.pick_priority(aging_limit),
+        };
+        self.len -= 1;
+        self.queues[i].pop_front()
     }
 
-    pub fn name(&self) -> &str {
-        self.name.as_str()
+    fn pick_weighted(&mut self) -> usize {
+        loop {
+            for _ in 0..self.queues.len() {
+                let i = self.cursor;
+                if self.credits[i] > 0 && !self.queues[i].is_empty() {
+                    self.credits[i] -= 1;
+                    return i;
+                }
+                self.cursor = (self.cursor + 1) % self.queues.len();
+            }
+            // all lanes that have tasks run out of credits, start a new round.
+            if let Policy::Weighted(ref weights) = self.policy {
+                self.credits.clone_from(weights);
+            }
+            self.cursor = 0;
+        }
     }
 
-    /// Stop the worker thread.
-    pub fn stop(&mut self) -> Option<thread::JoinHandle<()>> {
-        // close sender explicitly so the background thread will exit.
-        info!("stoping {}", self.name);
-        if self.handle.is_none() {
-            return None;
+    fn pick_priority(&mut self, aging_limit: usize) -> usize {
+        let first = self.queues.iter().position(|q| !q.is_empty()).unwrap();
+        let aged = (first + 1..self.queues.len())
+            .find(|&i| !self.queues[i].is_empty() && self.skipped[i] >= aging_limit);
+        if let Some(i) = aged {
+            self.skipped[i] = 0;
+            return i;
         }
-        if let Err(e) = self.scheduler.sender.send(None) {
-            warn!("failed to stop worker thread: {:?}", e);
+        for i in first + 1..self.queues.len() {
+            if !self.queues[i].is_empty() {
+                self.skipped[i] += 1;
+            }
         }
-        self.handle.take()
+        self.skipped[first] = 0;
+        first
     }
 }
 
-#[cfg(test)]
-mod test {
-    use std::thread;
-    use std::sync::Arc;
-    use std::sync::atomic::*;
-    use std::cmp;
-    use std::time::Duration;
-
-    use super::*;
+/// A token bucket that limits how fast tasks are dispatched.
+struct TokenBucket {
+    // tokens added per second.
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, burst: usize) -> TokenBucket {
+        TokenBucket {
+            rate: rate,
+            capacity: burst as f64,
+            tokens: burst as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Get how many tasks can be dispatched now, or how long to wait for the
+    /// next token if none.
+    fn available(&mut self) -> Result<usize, Duration> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill);
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        self.tokens = (self.tokens + secs * self.rate).min(self.capacity);
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            return Ok(self.tokens as usize);
+        }
+        let wait = (1.0 - self.tokens) / self.rate;
+        Err(Duration::new(wait as u64, (wait.fract() * 1e9) as u32))
+    }
+
+    fn consume(&mut self, count: usize) {
+        self.tokens -= count as f64;
+    }
+}
+
+/// The tasks a `ControlledBatchRunnable` left in the buffer of last batch.
+#[derive(Clone, Copy)]
+struct Carried {
+    // when the oldest of them was scheduled.
+    enqueued_at: Instant,
+    // the trace ID of the batch they were left from.
+    trace_id: Option<u64>,
+    // the batch they were left from handled none of its tasks.
+    stalled: bool,
+}
+
+/// The result of filling a batch.
+enum Fetched {
+    Batch(BatchMeta),
+    Timeout,
+    Exit,
+}
+
+/// The receiving side of a worker, shared by all its threads.
+struct Inbox<T> {
+    rx: Receiver<Option<Envelope<T>>>,
+    lanes: Lanes<T>,
+    stopping: bool,
+    // wake up at least once every `heartbeat` even if there is no task.
+    heartbeat: Option<Duration>,
+    limiter: Option<TokenBucket>,
+}
+
+impl<T: Display> Inbox<T> {
+    /// Fill the buffer with at most `batch_size` tasks, waits at most `timeout`
+    /// if there is no task.
+    ///
+    /// The buffer may contain leftovers of last batch, which are described by
+    /// `carried`.
+    fn fill(&mut self,
+            shared: &Shared,
+            batch_size: usize,
+            timeout: Option<Duration>,
+            carried: Option<Carried>,
+            buffer: &mut Vec<T>,
+            label: &mut BatchLabel)
+            -> Fetched {
+        let timeout = match (timeout, self.heartbeat) {
+            (Some(t), Some(h)) => Some(cmp::min(t, h)),
+            (t, h) => t.or(h),
+        };
+        if self.lanes.is_empty() && buffer.is_empty() {
+            if self.stopping {
+                return Fetched::Exit;
+            }
+            let res = match timeout {
+                None => self.rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                Some(dur) => self.rx.recv_timeout(dur),
+            };
+            match res {
+                Ok(Some(e)) => self.lanes.push(e),
+                Err(RecvTimeoutError::Timeout) => return Fetched::Timeout,
+                _ => {
+                    self.stopping = true;
+                    return Fetched::Exit;
+                }
+            }
+        }
+        let stalled = carried.map_or(false, |c| c.stalled);
+        if stalled && !self.stopping {
+            // the runner made no progress, wait a while for new tasks instead of
+            // handing it the same batch at once.
+            match self.rx.recv_timeout(Duration::from_millis(STALLED_BACKOFF_MILLIS)) {
+                Ok(Some(e)) => self.lanes.push(e),
+                Err(RecvTimeoutError::Timeout) => {}
+                _ => self.stopping = true,
+            }
+        }
+        while !self.stopping {
+            match self.rx.try_recv() {
+                Ok(None) => self.stopping = true,
+                Ok(Some(e)) => self.lanes.push(e),
+                _ => break,
+            }
+        }
+        if stalled && self.stopping {
+            // the runner may never finish them, don't keep the worker from exiting.
+            self.drop_leftovers(shared, buffer, label);
+            self.drop_queued(shared);
+            return Fetched::Exit;
+        }
+        let mut limit = batch_size;
+        if buffer.is_empty() {
+            match self.throttle(shared, timeout) {
+                Some(tokens) => limit = cmp::min(limit, tokens),
+                None if self.stopping => return Fetched::Exit,
+                None => return Fetched::Timeout,
+            }
+        }
+        let mut oldest_enqueued_at = carried.map(|c| c.enqueued_at);
+        // the trace shared by the tasks so far, Some(None) once any of them is
+        // not traced or they mix traces.
+        let mut trace = carried.map(|c| c.trace_id);
+        let mut dispatched = 0;
+        while buffer.len() < limit {
+            let e = match self.lanes.pop() {
+                Some(e) => e,
+                None => break,
+            };
+            label.push(&e);
+            trace = match trace {
+                Some(id) if id != e.trace_id => Some(None),
+                Some(id) => Some(id),
+                None => Some(e.trace_id),
+            };
+            shared.lane_pending[e.lane].fetch_sub(1, Ordering::SeqCst);
+            if let Some(c) = e.class {
+                shared.class_pending[c].fetch_sub(1, Ordering::SeqCst);
+            }
+            shared.on_dispatched(e.seq as usize);
+            oldest_enqueued_at = match oldest_enqueued_at {
+                Some(t) if t <= e.enqueued_at => Some(t),
+                _ => Some(e.enqueued_at),
+            };
+            buffer.push(e.task);
+            dispatched += 1;
+        }
+        if let Some(ref mut limiter) = self.limiter {
+            limiter.consume(dispatched);
+        }
+        shared.pending.fetch_sub(buffer.len(), Ordering::SeqCst);
+        shared.update_busy();
+        let oldest_enqueued_at = oldest_enqueued_at.unwrap();
+        Fetched::Batch(BatchMeta {
+            oldest_enqueued_at: oldest_enqueued_at,
+            wait: oldest_enqueued_at.elapsed(),
+            batch_seq: shared.batches.fetch_add(1, Ordering::SeqCst) as u64 + 1,
+            trace_id: trace.and_then(|id| id),
+        })
+    }
+
+    /// Wait until the rate limit allows dispatching at least one task, and
+    /// return how many tasks can be dispatched. None if it still doesn't after
+    /// `timeout`, or the worker is stopping.
+    ///
+    /// Tasks received while waiting are queued. Once the worker is stopping,
+    /// the tasks that run out of tokens are dropped instead of waiting, so
+    /// the worker exits promptly without bursting them to the runner.
+    fn throttle(&mut self, shared: &Shared, timeout: Option<Duration>) -> Option<usize> {
+        let start = Instant::now();
+        loop {
+            let wait = match self.limiter.as_mut().map(|l| l.available()) {
+                None => return Some(usize::MAX),
+                Some(Ok(tokens)) => return Some(tokens),
+                Some(Err(wait)) => wait,
+            };
+            if self.stopping {
+                self.drop_queued(shared);
+                return None;
+            }
+            let wait = match timeout {
+                Some(t) => {
+                    let elapsed = start.elapsed();
+                    if elapsed >= t {
+                        return None;
+                    }
+                    cmp::min(wait, t - elapsed)
+                }
+                None => wait,
+            };
+            match self.rx.recv_timeout(wait) {
+                Ok(Some(e)) => self.lanes.push(e),
+                Err(RecvTimeoutError::Timeout) => {}
+                _ => self.stopping = true,
+            }
+        }
+    }
+
+    /// Drop the tasks carried over from last batch, they are counted as rejected.
+    fn drop_leftovers(&self, shared: &Shared, buffer: &mut Vec<T>, label: &mut BatchLabel) {
+        let count = buffer.len();
+        buffer.clear();
+        label.clear();
+        shared.leftovers.fetch_sub(count, Ordering::SeqCst);
+        shared.pending.fetch_sub(count, Ordering::SeqCst);
+        shared.update_busy();
+        warn!("{} is stopping, drop {} leftover tasks", shared.name, count);
+        shared.on_rejected(count);
+    }
+
+    /// Drop all the queued tasks, they are counted as rejected.
+    fn drop_queued(&mut self, shared: &Shared) {
+        let mut count = 0;
+        while let Some(e) = self.lanes.pop() {
+            shared.lane_pending[e.lane].fetch_sub(1, Ordering::SeqCst);
+            if let Some(c) = e.class {
+                shared.class_pending[c].fetch_sub(1, Ordering::SeqCst);
+            }
+            count += 1;
+        }
+        if count == 0 {
+            return;
+        }
+        shared.pending.fetch_sub(count, Ordering::SeqCst);
+        shared.update_busy();
+        warn!("{} is stopping, drop {} queued tasks", shared.name, count);
+        shared.on_rejected(count);
+    }
+}
+
+fn poll<H, T>(mut handler: H,
+              scheduler: Scheduler<T>,
+              inbox: Arc<Mutex<Inbox<T>>>,
+              shared: Arc<Shared>,
+              batch_size: usize,
+              format_tasks: bool,
+              index: usize)
+    where H: Handler<T> + Send + 'static,
+          T: Display + Send + 'static
+{
+    defer!({
+        // a thread that panics in the runner leaves its label behind.
+        if let Ok(mut current) = shared.current.lock() {
+            current[index] = None;
+        }
+        shared.running.fetch_sub(1, Ordering::SeqCst);
+    });
+    handler.on_start(scheduler);
+    let mut buffer = Vec::with_capacity(batch_size);
+    let mut label = BatchLabel::new(format_tasks);
+    let mut carried = None;
+    // when to call `on_timeout`, it's kept across heartbeats.
+    let mut deadline = None;
+    let mut renew_deadline = true;
+    loop {
+        shared.touch();
+        let now = Instant::now();
+        if renew_deadline {
+            deadline = handler.timeout().map(|t| now + t);
+            renew_deadline = false;
+        }
+        let timeout = deadline.map(|d| if d > now { d - now } else { Duration::new(0, 0) });
+        // threads of a pool take turns to fetch tasks.
+        let fetched = inbox.lock()
+            .unwrap()
+            .fill(&shared,
+                  batch_size,
+                  timeout,
+                  carried,
+                  &mut buffer,
+                  &mut label);
+        let meta = match fetched {
+            Fetched::Batch(meta) => meta,
+            Fetched::Timeout => {
+                if deadline.map_or(false, |d| d <= Instant::now()) {
+                    handler.on_timeout();
+                    renew_deadline = true;
+                }
+                continue;
+            }
+            Fetched::Exit => {
+                // the worker is stopped, or all its schedulers are dropped.
+                shared.stopped.store(true, Ordering::SeqCst);
+                return;
+            }
+        };
+        if label.leftovers > 0 {
+            // the leftovers are dispatched again with the batch.
+            shared.leftovers.fetch_sub(label.leftovers, Ordering::SeqCst);
+        }
+        shared.touch();
+        renew_deadline = true;
+        shared.current.lock().unwrap()[index] = Some(label);
+        let count = buffer.len();
+        let timer = SlowTimer::new();
+        CURRENT_TRACE_ID.with(|id| id.set(meta.trace_id));
+        handler.handle(&mut buffer, &meta);
+        CURRENT_TRACE_ID.with(|id| id.set(None));
+        if !handler.keep_leftovers() {
+            buffer.clear();
+        }
+        let left = buffer.len();
+        assert!(left <= count,
+                "{} gets {} tasks back from a batch of {}, ControlledBatchRunnable must not \
+                 add tasks to the batch",
+                shared.name,
+                left,
+                count);
+        let handled = shared.handled.fetch_add(count - left, Ordering::SeqCst);
+        label = shared.current.lock().unwrap()[index].take().unwrap();
+        if shared.sampled(handled, handled + count - left) {
+            info!("{} handled batch {} [size {}] [pending {}]",
+                  shared.name,
+                  label,
+                  count,
+                  shared.pending.load(Ordering::SeqCst));
+        }
+        if timer.is_slow() || meta.wait >= Duration::from_secs(SLOW_WAIT_SECS) {
+            warn!("handle task {} [waits {:?}] [takes {:?}]",
+                  label,
+                  meta.wait,
+                  timer.elapsed());
+        }
+        label.clear();
+        carried = None;
+        if left > 0 {
+            // leftovers are handled at the front of next batch.
+            shared.leftovers.fetch_add(left, Ordering::SeqCst);
+            shared.pending.fetch_add(left, Ordering::SeqCst);
+            shared.update_busy();
+            label.leftovers = left;
+            carried = Some(Carried {
+                enqueued_at: meta.oldest_enqueued_at,
+                trace_id: meta.trace_id,
+                stalled: left == count,
+            });
+        }
+    }
+}
+
+const STOP_CHECK_INTERVAL_MILLIS: u64 = 10;
+// how long to wait before handing leftovers back to a runner that handled
+// none of them.
+const STALLED_BACKOFF_MILLIS: u64 = 10;
+// tasks waiting in the queue longer than this are logged even if they run fast.
+const SLOW_WAIT_SECS: u64 = 1;
+const DEFAULT_SCOPED_STOP_TIMEOUT_SECS: u64 = 10;
+
+// Linux only shows the first 15 bytes of a thread name.
+const MAX_THREAD_NAME_LEN: usize = 15;
+
+/// Get the name of the `index`th thread of a pool with `count` threads.
+///
+/// The base name is truncated so that the zero-padded index always stays in
+/// the visible part of the name.
+fn pool_thread_name(name: &str, index: usize, count: usize) -> String {
+    let width = format!("{}", cmp::max(count, 1) - 1).len();
+    let suffix = format!("-{:0width$}", index, width = width);
+    let mut base_len = cmp::min(name.len(), MAX_THREAD_NAME_LEN.saturating_sub(suffix.len()));
+    while !name.is_char_boundary(base_len) {
+        base_len -= 1;
+    }
+    format!("{}{}", &name[..base_len], suffix)
+}
+
+impl<T: Display + Send + 'static> Worker<T> {
+    /// Create a worker.
+    pub fn new<S: Into<String>>(name: S) -> Worker<T> {
+        Worker::with_lanes(name, &[1])
+    }
+
+    /// Create a worker that is fed by `weights.len()` lanes.
+    ///
+    /// When all lanes have tasks queued, they are drained proportionally to their
+    /// weights, otherwise whichever has tasks is drained. Use `lane_scheduler` to
+    /// get the scheduler of a lane, `scheduler` always returns the first one.
+    pub fn with_lanes<S: Into<String>>(name: S, weights: &[usize]) -> Worker<T> {
+        assert!(!weights.is_empty() && weights.iter().all(|&w| w > 0),
+                "invalid lane weights {:?}",
+                weights);
+        Worker::with_policy(name, Policy::Weighted(weights.to_vec()))
+    }
+
+    /// Create a worker that is fed by `lanes` prioritized lanes.
+    ///
+    /// Tasks in lane 0 have the highest priority. To avoid starvation, a lane
+    /// is picked anyway after being skipped for a while, see `set_aging_limit`.
+    pub fn with_priorities<S: Into<String>>(name: S, lanes: usize) -> Worker<T> {
+        assert!(lanes > 0);
+        Worker::with_policy(name,
+                            Policy::Priority {
+                                lanes: lanes,
+                                aging_limit: DEFAULT_AGING_LIMIT,
+                            })
+    }
+
+    fn with_policy<S: Into<String>>(name: S, policy: Policy) -> Worker<T> {
+        let (tx, rx) = mpsc::channel();
+        let name = name.into();
+        let shared = Arc::new(Shared::new(name.clone(), policy.lanes()));
+        Worker {
+            name: name,
+            scheduler: Scheduler::new(0, shared, tx),
+            policy: policy,
+            detect_stall: true,
+            receiver: Mutex::new(Some(rx)),
+            handle: None,
+            heartbeat: Some(Duration::from_secs(DEFAULT_HEARTBEAT_INTERVAL_SECS)),
+            rate_limit: None,
+        }
+    }
+
+    /// Set how often the idle threads wake up to report they are alive, see
+    /// `last_active`. None means never, must be called before start.
+    pub fn set_heartbeat_interval(&mut self, interval: Option<Duration>) {
+        self.heartbeat = interval;
+    }
+
+    /// Dispatch at most `rate` tasks per second on average, allowing bursts of
+    /// up to `burst` tasks, must be called before start.
+    ///
+    /// Throttled tasks stay pending, so they still count for `is_busy`. When
+    /// the worker stops, the tasks still throttled are dropped and counted as
+    /// rejected rather than dispatched at once.
+    pub fn set_max_tasks_per_sec(&mut self, rate: f64, burst: usize) {
+        assert!(rate > 0.0 && burst > 0,
+                "invalid rate limit {}/s, burst {}",
+                rate,
+                burst);
+        self.rate_limit = Some((rate, burst));
+    }
+
+    /// Set how many times a lane can be skipped by higher priority lanes before
+    /// forcing one of its tasks to be handled, must be called before start.
+    ///
+    /// It's a no-op for workers not created by `with_priorities`.
+    pub fn set_aging_limit(&mut self, limit: usize) {
+        if let Policy::Priority { ref mut aging_limit, .. } = self.policy {
+            *aging_limit = limit;
+        }
+    }
+
+    /// Log one of every `interval` scheduled tasks, and the batch that makes
+    /// the handled tasks reach a multiple of `interval`, at info level.
+    ///
+    /// 0 means never, which is the default.
+    pub fn set_log_sample_interval(&mut self, interval: usize) {
+        self.scheduler.shared.log_sample_interval.store(interval, Ordering::Relaxed);
+    }
+
+    /// Set the watermarks of the busy state.
+    ///
+    /// The worker becomes busy once its pending tasks reach `high`, and stays
+    /// busy until they drop to `low`. By default `high` is 1 and `low` is 0,
+    /// that is the worker is busy whenever there is any pending task.
+    pub fn set_busy_watermarks(&mut self, high: usize, low: usize) {
+        assert!(low < high, "invalid busy watermarks {}/{}", high, low);
+        let shared = &self.scheduler.shared;
+        shared.busy_high.store(high, Ordering::SeqCst);
+        shared.busy_low.store(low, Ordering::SeqCst);
+        shared.update_busy();
+    }
+
+    /// Limit the pending tasks of every class, `limits[i]` is the limit of class i.
+    ///
+    /// It must be called before any task is scheduled or any scheduler is
+    /// cloned from the worker.
+    pub fn set_class_limits(&mut self, limits: &[usize])
+        where T: TaskClass
+    {
+        assert_eq!(limits.len(), T::class_count());
+        {
+            let shared = Arc::get_mut(&mut self.scheduler.shared)
+                .expect("class limits should be set before sharing the scheduler");
+            assert_eq!(shared.pending.load(Ordering::SeqCst), 0);
+            shared.class_pending = limits.iter().map(|_| AtomicUsize::new(0)).collect();
+            shared.class_limits = limits.to_vec();
+        }
+        self.scheduler.classify = Some(T::class);
+    }
+
+    /// Start the worker.
+    pub fn start<R: Runnable<T> + Send + 'static>(&mut self, runner: R) -> Result<(), io::Error> {
+        self.start_impl(Plain(runner), 1, true)
+    }
+
+    /// Start the worker with a runner that handles at most `batch_size` tasks
+    /// at a time, a `batch_size` of 0 is treated as 1.
+    pub fn start_batch<R>(&mut self, runner: R, batch_size: usize) -> Result<(), io::Error>
+        where R: BatchRunnable<T> + Send + 'static
+    {
+        self.start_impl(Plain(runner), batch_size, false)
+    }
+
+    /// Start the worker with a runner that may leave some tasks of a batch
+    /// unhandled, see `ControlledBatchRunnable`.
+    pub fn start_controlled_batch<R>(&mut self,
+                                     runner: R,
+                                     batch_size: usize)
+                                     -> Result<(), io::Error>
+        where R: ControlledBatchRunnable<T> + Send + 'static
+    {
+        self.start_impl(Controlled(runner), batch_size, false)
+    }
+
+    fn start_impl<H>(&mut self,
+                     handler: H,
+                     batch_size: usize,
+                     format_tasks: bool)
+                     -> Result<(), io::Error>
+        where H: Handler<T> + Send + 'static
+    {
+        let mut receiver = self.receiver.lock().unwrap();
+        info!("starting working thread: {}", self.name);
+        if receiver.is_none() {
+            warn!("worker {} has been started.", self.name);
+            return Ok(());
+        }
+
+        // a batch always holds at least one task, or no task can be dispatched.
+        let batch_size = cmp::max(batch_size, 1);
+        let inbox = self.new_inbox(receiver.take().unwrap());
+        let shared = self.scheduler.shared.clone();
+        shared.set_threads(1);
+        let scheduler = self.scheduler.clone();
+        let h = try!(Builder::new()
+            .name(thd_name!(self.name.clone()))
+            .spawn(move || poll(handler, scheduler, inbox, shared, batch_size, format_tasks, 0)));
+        self.handle = Some(h);
+        self.on_started();
+        Ok(())
+    }
+
+    /// Start the worker with `thread_count` threads, each of them runs a clone
+    /// of `runner`.
+    ///
+    /// Threads are named `{name}-{index}`.
+    pub fn start_pool<R>(&mut self, runner: R, thread_count: usize) -> Result<(), io::Error>
+        where R: Runnable<T> + Clone + Send + 'static
+    {
+        assert!(thread_count > 0);
+        let mut receiver = self.receiver.lock().unwrap();
+        info!("starting {} working threads: {}", thread_count, self.name);
+        if receiver.is_none() {
+            warn!("worker {} has been started.", self.name);
+            return Ok(());
+        }
+
+        let inbox = self.new_inbox(receiver.take().unwrap());
+        let shared = self.scheduler.shared.clone();
+        shared.set_threads(thread_count);
+        let mut handles = Vec::with_capacity(thread_count - 1);
+        for i in 1..thread_count {
+            let (runner, inbox, shared) = (runner.clone(), inbox.clone(), shared.clone());
+            let scheduler = self.scheduler.clone();
+            let res = Builder::new()
+                .name(thd_name!(pool_thread_name(&self.name, i, thread_count)))
+                .spawn(move || poll(Plain(runner), scheduler, inbox, shared, 1, true, i));
+            match res {
+                Ok(h) => handles.push(h),
+                Err(e) => {
+                    // let the threads that have been spawned exit.
+                    self.scheduler.shared.stopped.store(true, Ordering::SeqCst);
+                    let _ = self.scheduler.sender.send(None);
+                    return Err(e);
+                }
+            }
+        }
+        // the first thread joins the others after exiting, so that joining it
+        // is enough to wait for the whole pool.
+        let scheduler = self.scheduler.clone();
+        let res = Builder::new()
+            .name(thd_name!(pool_thread_name(&self.name, 0, thread_count)))
+            .spawn(move || {
+                poll(Plain(runner), scheduler, inbox, shared, 1, true, 0);
+                for h in handles {
+                    if let Err(e) = h.join() {
+                        error!("worker thread panicked: {:?}", e);
+                    }
+                }
+            });
+        match res {
+            Ok(h) => self.handle = Some(h),
+            Err(e) => {
+                self.scheduler.shared.stopped.store(true, Ordering::SeqCst);
+                let _ = self.scheduler.sender.send(None);
+                return Err(e);
+            }
+        }
+        self.on_started();
+        Ok(())
+    }
+
+    fn on_started(&self) {
+        if self.detect_stall {
+            register(&self.name, &self.scheduler.shared);
+        }
+    }
+
+    /// Don't let `StallDetector` check this worker, must be called before start.
+    ///
+    /// Useful for tests that block workers on purpose.
+    pub fn disable_stall_detection(&mut self) {
+        self.detect_stall = false;
+    }
+
+    fn new_inbox(&self, rx: Receiver<Option<Envelope<T>>>) -> Arc<Mutex<Inbox<T>>> {
+        Arc::new(Mutex::new(Inbox {
+            rx: rx,
+            lanes: Lanes::new(self.policy.clone()),
+            stopping: false,
+            heartbeat: self.heartbeat,
+            limiter: self.rate_limit.map(|(rate, burst)| TokenBucket::new(rate, burst)),
+        }))
+    }
+
+    /// Get a scheduler to schedule task.
+    pub fn scheduler(&self) -> Scheduler<T> {
+        self.scheduler.clone()
+    }
+
+    /// Get a scheduler to schedule task to the specified lane.
+    pub fn lane_scheduler(&self, lane: usize) -> Scheduler<T> {
+        assert!(lane < self.policy.lanes(), "lane {} out of range", lane);
+        let mut scheduler = self.scheduler.clone();
+        scheduler.lane = lane;
+        scheduler
+    }
+
+    /// Schedule a task to run.
+    ///
+    /// If the worker is stopped, an error will return.
+    pub fn schedule(&self, task: T) -> Result<(), ScheduleError<T>> {
+        self.scheduler.schedule(task)
+    }
+
+    pub fn schedule_traced(&self, task: T, trace_id: u64) -> Result<(), ScheduleError<T>> {
+        self.scheduler.schedule_traced(task, trace_id)
+    }
+
+    pub fn schedule_all(&self, tasks: Vec<T>) -> Result<(), ScheduleError<Vec<T>>> {
+        self.scheduler.schedule_all(tasks)
+    }
+
+    /// Check if underlying worker can't handle task immediately.
+    pub fn is_busy(&self) -> bool {
+        self.handle.is_none() || self.scheduler.is_busy()
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    /// Get the count of threads the worker is started with.
+    pub fn thread_count(&self) -> usize {
+        self.scheduler.shared.threads.load(Ordering::SeqCst)
+    }
+
+    /// Get the statistics of the worker.
+    pub fn stats(&self) -> WorkerStats {
+        self.scheduler.stats()
+    }
+
+    /// Get the labels of the tasks being handled, `None` if the worker is idle.
+    ///
+    /// Tasks of workers not started with a `Runnable` are only identified by
+    /// their sequence numbers.
+    pub fn current_task(&self) -> Option<String> {
+        self.scheduler.shared.current_task()
+    }
+
+    /// Get the last time a thread of the worker woke up, either to handle tasks
+    /// or because of heartbeat. None if the worker is not started yet.
+    ///
+    /// It stops moving when the threads are blocked by the runner or exit.
+    pub fn last_active(&self) -> Option<Instant> {
+        self.scheduler.shared.last_active()
+    }
+
+    /// Stop the worker thread.
+    pub fn stop(&mut self) -> Option<thread::JoinHandle<()>> {
+        // close sender explicitly so the background thread will exit.
+        info!("stoping {} [rejected {} tasks]",
+              self.name,
+              self.scheduler.rejected_count());
+        if self.handle.is_none() {
+            return None;
+        }
+        self.scheduler.shared.stopped.store(true, Ordering::SeqCst);
+        if let Err(e) = self.scheduler.sender.send(None) {
+            warn!("failed to stop worker thread: {:?}", e);
+        }
+        self.handle.take()
+    }
+
+    /// Stop the worker and wait for all its threads to exit for at most `timeout`.
+    pub fn stop_timeout(&mut self, timeout: Duration) -> Result<(), io::Error> {
+        let h = match self.stop() {
+            Some(h) => h,
+            None => return Ok(()),
+        };
+        let start = Instant::now();
+        while self.scheduler.shared.running.load(Ordering::SeqCst) > 0 {
+            if start.elapsed() >= timeout {
+                return Err(io::Error::new(io::ErrorKind::TimedOut,
+                                          format!("{} failed to stop in {:?}, current task: {:?}",
+                                                  self.name,
+                                                  timeout,
+                                                  self.current_task())));
+            }
+            thread::sleep(Duration::from_millis(STOP_CHECK_INTERVAL_MILLIS));
+        }
+        if h.join().is_err() {
+            return Err(io::Error::new(io::ErrorKind::Other,
+                                      format!("{} exited abnormally", self.name)));
+        }
+        Ok(())
+    }
+}
+
+/// A guard that stops the worker and waits for it to exit when dropped.
+///
+/// Useful for tests and short-lived tools, so that worker threads are not
+/// leaked even if a panic happens before the worker is stopped.
+pub struct ScopedWorker<T: Display + Send + 'static> {
+    worker: Option<Worker<T>>,
+    timeout: Duration,
+}
+
+impl<T: Display + Send + 'static> ScopedWorker<T> {
+    pub fn new(worker: Worker<T>) -> ScopedWorker<T> {
+        ScopedWorker::with_timeout(worker, Duration::from_secs(DEFAULT_SCOPED_STOP_TIMEOUT_SECS))
+    }
+
+    /// Create a guard that waits for at most `timeout` when dropped.
+    pub fn with_timeout(worker: Worker<T>, timeout: Duration) -> ScopedWorker<T> {
+        ScopedWorker {
+            worker: Some(worker),
+            timeout: timeout,
+        }
+    }
+
+    /// Take the worker back, the caller is responsible to stop it then.
+    pub fn into_inner(mut self) -> Worker<T> {
+        self.worker.take().unwrap()
+    }
+}
+
+impl<T: Display + Send + 'static> Deref for ScopedWorker<T> {
+    type Target = Worker<T>;
+
+    fn deref(&self) -> &Worker<T> {
+        self.worker.as_ref().unwrap()
+    }
+}
+
+impl<T: Display + Send + 'static> DerefMut for ScopedWorker<T> {
+    fn deref_mut(&mut self) -> &mut Worker<T> {
+        self.worker.as_mut().unwrap()
+    }
+}
+
+impl<T: Display + Send + 'static> Drop for ScopedWorker<T> {
+    fn drop(&mut self) {
+        if let Some(mut worker) = self.worker.take() {
+            if let Err(e) = worker.stop_timeout(self.timeout) {
+                error!("failed to stop scoped worker: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::*;
+    use std::cmp;
+    use std::time::{Duration, Instant};
+
+    use util::panic_hook;
+    use super::*;
 
     struct CountRunner {
         count: Arc<AtomicUsize>,
@@ -269,7 +1765,7 @@ mod test {
 
     #[test]
     fn test_threaded() {
-        let mut worker = Worker::new("test-worker-threaded");
+        let mut worker = ScopedWorker::new(Worker::new("test-worker-threaded"));
         let count = Arc::new(AtomicUsize::new(0));
         worker.start(CountRunner { count: count.clone() }).unwrap();
         let scheduler = worker.scheduler();
@@ -283,19 +1779,1040 @@ mod test {
             }
             thread::sleep(Duration::from_millis(1));
         }
-        worker.stop().unwrap().join().unwrap();
+        drop(worker);
         assert_eq!(count.load(Ordering::SeqCst), 200);
     }
 
     #[test]
     fn test_batch() {
-        let mut worker = Worker::new("test-worker-batch");
         let count = Arc::new(AtomicUsize::new(0));
-        worker.start_batch(BatchRunner { count: count.clone() }, 10).unwrap();
-        for _ in 0..20 {
-            worker.schedule(50).unwrap();
+        {
+            let mut worker = ScopedWorker::new(Worker::new("test-worker-batch"));
+            worker.start_batch(BatchRunner { count: count.clone() }, 10).unwrap();
+            for _ in 0..20 {
+                worker.schedule(50).unwrap();
+            }
         }
-        worker.stop().unwrap().join().unwrap();
         assert_eq!(count.load(Ordering::SeqCst), 20 * 50);
     }
+
+    #[test]
+    fn test_zero_batch_size() {
+        let count = Arc::new(AtomicUsize::new(0));
+        {
+            let mut worker = ScopedWorker::new(Worker::new("test-worker-batch-zero"));
+            worker.start_batch(BatchRunner { count: count.clone() }, 0).unwrap();
+            for _ in 0..3 {
+                worker.schedule(1).unwrap();
+            }
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+
+        let records = Arc::new(Mutex::new(vec![]));
+        {
+            let mut worker = ScopedWorker::new(Worker::new("test-worker-controlled-zero"));
+            let runner = PartialRunner {
+                limit: 1,
+                records: records.clone(),
+            };
+            worker.start_controlled_batch(runner, 0).unwrap();
+            for i in 0..3 {
+                worker.schedule(i).unwrap();
+            }
+        }
+        // every batch holds a single task.
+        let records = records.lock().unwrap();
+        let batches: Vec<_> = records.iter().map(|&(_, ref b)| b.clone()).collect();
+        assert_eq!(batches, vec![vec![0], vec![1], vec![2]]);
+    }
+
+    #[test]
+    fn test_schedule_seq() {
+        let mut worker = ScopedWorker::new(Worker::new("test-worker-seq"));
+        let count = Arc::new(AtomicUsize::new(0));
+        worker.start(CountRunner { count: count.clone() }).unwrap();
+        let mut handles = vec![];
+        for _ in 0..4 {
+            let scheduler = worker.scheduler();
+            handles.push(thread::spawn(move || {
+                (0..25).map(|_| scheduler.schedule_seq(0).unwrap()).collect::<Vec<_>>()
+            }));
+        }
+        let mut all = vec![];
+        for h in handles {
+            let seqs = h.join().unwrap();
+            // sequences seen by one producer are strictly increasing.
+            for w in seqs.windows(2) {
+                assert!(w[0] < w[1], "{:?}", seqs);
+            }
+            all.extend(seqs);
+        }
+        all.sort();
+        assert_eq!(all, (1..101).collect::<Vec<_>>());
+        assert_eq!(worker.stats().last_scheduled_seq, 100);
+
+        let scheduler = worker.scheduler();
+        drop(worker);
+        let stats = scheduler.stats();
+        assert_eq!(stats.last_dispatched_seq, 100);
+        assert_eq!(stats.backlog(), 0);
+        assert_eq!(stats.pending, 0);
+    }
+
+    struct RecordRunner {
+        records: Arc<Mutex<Vec<u64>>>,
+    }
+
+    impl Runnable<u64> for RecordRunner {
+        fn run(&mut self, t: u64) {
+            self.records.lock().unwrap().push(t);
+        }
+    }
+
+    #[test]
+    fn test_weighted_lanes() {
+        let mut worker = ScopedWorker::new(Worker::with_lanes("test-worker-lanes", &[3, 1]));
+        let (fast, slow) = (worker.lane_scheduler(0), worker.lane_scheduler(1));
+        // saturate both lanes before the worker starts.
+        for _ in 0..30 {
+            fast.schedule(0).unwrap();
+        }
+        for _ in 0..20 {
+            slow.schedule(1).unwrap();
+        }
+        assert_eq!(worker.stats().lane_pending, vec![30, 20]);
+        assert_eq!(worker.stats().pending, 50);
+
+        let records = Arc::new(Mutex::new(vec![]));
+        worker.start(RecordRunner { records: records.clone() }).unwrap();
+        drop(worker);
+
+        let records = records.lock().unwrap();
+        assert_eq!(records.len(), 50);
+        // while both lanes have tasks, 3 fast tasks are handled for every slow one.
+        for chunk in records[..40].chunks(4) {
+            assert_eq!(chunk.iter().filter(|&&t| t == 0).count(), 3, "{:?}", records);
+        }
+        // fast lane is drained, only slow tasks are left.
+        assert!(records[40..].iter().all(|&t| t == 1), "{:?}", records);
+        assert_eq!(fast.stats().lane_pending, vec![0, 0]);
+        assert_eq!(fast.stats().pending, 0);
+    }
+
+    #[test]
+    fn test_priority_aging() {
+        let mut worker = ScopedWorker::new(Worker::with_priorities("test-worker-priority", 2));
+        worker.set_aging_limit(4);
+        let (high, low) = (worker.lane_scheduler(0), worker.lane_scheduler(1));
+        for _ in 0..40 {
+            high.schedule(0).unwrap();
+        }
+        for _ in 0..10 {
+            low.schedule(1).unwrap();
+        }
+        high.schedule(0).unwrap();
+
+        let records = Arc::new(Mutex::new(vec![]));
+        worker.start(RecordRunner { records: records.clone() }).unwrap();
+        drop(worker);
+
+        let records = records.lock().unwrap();
+        assert_eq!(records.len(), 51);
+        // one low priority task is forced in after every 4 high priority ones.
+        for chunk in records[..50].chunks(5) {
+            assert_eq!(chunk, &[0, 0, 0, 0, 1], "{:?}", records);
+        }
+        assert_eq!(records[50], 0);
+        assert_eq!(high.stats().lane_pending, vec![0, 0]);
+    }
+
+    #[test]
+    fn test_priority() {
+        let worker = Worker::with_priorities("test-worker-priority-strict", 2);
+        let mut worker = ScopedWorker::new(worker);
+        let (high, low) = (worker.lane_scheduler(0), worker.lane_scheduler(1));
+        for _ in 0..5 {
+            low.schedule(1).unwrap();
+            high.schedule(0).unwrap();
+        }
+
+        let records = Arc::new(Mutex::new(vec![]));
+        worker.start(RecordRunner { records: records.clone() }).unwrap();
+        drop(worker);
+        assert_eq!(*records.lock().unwrap(), vec![0, 0, 0, 0, 0, 1, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn test_backlog_with_lanes() {
+        let mut worker = ScopedWorker::new(Worker::with_priorities("test-worker-backlog", 2));
+        let (high, low) = (worker.lane_scheduler(0), worker.lane_scheduler(1));
+        for _ in 0..5 {
+            low.schedule(1).unwrap();
+        }
+        high.schedule(0).unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        worker.start(GateRunner { gate: rx }).unwrap();
+        // the newest task is dispatched first, the older ones are still pending.
+        wait_pending(&worker, 5);
+        let stats = worker.stats();
+        assert_eq!(stats.last_dispatched_seq, 6);
+        assert_eq!(stats.backlog(), 5);
+
+        tx.send(()).unwrap();
+        wait_pending(&worker, 4);
+        let stats = worker.stats();
+        assert_eq!(stats.last_dispatched_seq, 6);
+        assert_eq!(stats.backlog(), 4);
+        for _ in 0..5 {
+            tx.send(()).unwrap();
+        }
+    }
+
+    struct ChainRunner {
+        scheduler: Option<Scheduler<u64>>,
+        records: Arc<Mutex<Vec<u64>>>,
+    }
+
+    impl Runnable<u64> for ChainRunner {
+        fn run(&mut self, t: u64) {
+            self.records.lock().unwrap().push(t);
+            if t > 0 {
+                // schedule the follow-up task to itself.
+                self.scheduler.as_ref().unwrap().schedule(t - 1).unwrap();
+            }
+        }
+
+        fn on_start(&mut self, scheduler: Scheduler<u64>) {
+            self.scheduler = Some(scheduler);
+        }
+    }
+
+    #[test]
+    fn test_schedule_to_self() {
+        let records = Arc::new(Mutex::new(vec![]));
+        {
+            let mut worker = ScopedWorker::new(Worker::new("test-worker-chain"));
+            let runner = ChainRunner {
+                scheduler: None,
+                records: records.clone(),
+            };
+            worker.start(runner).unwrap();
+            worker.schedule(3).unwrap();
+            for _ in 0..100 {
+                if records.lock().unwrap().len() == 4 {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        assert_eq!(*records.lock().unwrap(), vec![3, 2, 1, 0]);
+    }
+
+    #[derive(Clone)]
+    struct StartRunner {
+        started: Arc<AtomicUsize>,
+    }
+
+    impl Runnable<u64> for StartRunner {
+        fn run(&mut self, _: u64) {}
+
+        fn on_start(&mut self, _: Scheduler<u64>) {
+            self.started.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    impl ControlledBatchRunnable<u64> for StartRunner {
+        fn run_batch(&mut self, ts: &mut Vec<u64>, _: &BatchMeta) {
+            ts.clear();
+        }
+
+        fn on_start(&mut self, _: Scheduler<u64>) {
+            self.started.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_on_start() {
+        let started = Arc::new(AtomicUsize::new(0));
+        let runner = StartRunner { started: started.clone() };
+        {
+            let mut worker = ScopedWorker::new(Worker::new("test-worker-start-batch"));
+            worker.start_batch(runner.clone(), 4).unwrap();
+        }
+        assert_eq!(started.load(Ordering::SeqCst), 1);
+        {
+            let mut worker = ScopedWorker::new(Worker::new("test-worker-start-controlled"));
+            worker.start_controlled_batch(runner.clone(), 4).unwrap();
+        }
+        assert_eq!(started.load(Ordering::SeqCst), 2);
+        {
+            let mut worker = ScopedWorker::new(Worker::new("test-worker-start-pool"));
+            worker.start_pool(runner, 3).unwrap();
+        }
+        // every thread of a pool gets its own call.
+        assert_eq!(started.load(Ordering::SeqCst), 5);
+    }
+
+    struct TraceRunner {
+        scheduler: Option<Scheduler<u64>>,
+        records: Arc<Mutex<Vec<(u64, Option<u64>)>>>,
+    }
+
+    impl Runnable<u64> for TraceRunner {
+        fn run(&mut self, t: u64) {
+            let trace_id = current_trace_id();
+            self.records.lock().unwrap().push((t, trace_id));
+            if t == 0 {
+                return;
+            }
+            let scheduler = self.scheduler.as_ref().unwrap();
+            match trace_id {
+                Some(id) => scheduler.schedule_traced(t - 1, id).unwrap(),
+                None => scheduler.schedule(t - 1).unwrap(),
+            }
+        }
+
+        fn on_start(&mut self, scheduler: Scheduler<u64>) {
+            self.scheduler = Some(scheduler);
+        }
+    }
+
+    #[test]
+    fn test_schedule_traced() {
+        let mut worker = ScopedWorker::new(Worker::new("test-worker-trace"));
+        worker.schedule_traced(2, 42).unwrap();
+        worker.schedule(1).unwrap();
+
+        let records = Arc::new(Mutex::new(vec![]));
+        let runner = TraceRunner {
+            scheduler: None,
+            records: records.clone(),
+        };
+        worker.start(runner).unwrap();
+        for _ in 0..100 {
+            if records.lock().unwrap().len() == 5 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        drop(worker);
+        assert_eq!(*records.lock().unwrap(),
+                   vec![(2, Some(42)), (1, None), (1, Some(42)), (0, None), (0, Some(42))]);
+        assert_eq!(current_trace_id(), None);
+    }
+
+    #[test]
+    fn test_batch_mixed_traces() {
+        let mut worker = ScopedWorker::new(Worker::new("test-worker-trace-batch"));
+        for _ in 0..4 {
+            worker.schedule_traced(0, 7).unwrap();
+        }
+        worker.schedule_traced(0, 7).unwrap();
+        worker.schedule_traced(0, 8).unwrap();
+        worker.schedule(0).unwrap();
+        worker.schedule_traced(0, 8).unwrap();
+        worker.schedule(0).unwrap();
+        for _ in 0..3 {
+            worker.schedule_traced(0, 9).unwrap();
+        }
+
+        let metas = Arc::new(Mutex::new(vec![]));
+        worker.start_batch(MetaRunner { metas: metas.clone() }, 4).unwrap();
+        drop(worker);
+        let traces: Vec<_> = metas.lock().unwrap().iter().map(|&(_, m)| m.trace_id).collect();
+        // only a batch whose tasks all belong to the same trace is traced.
+        assert_eq!(traces, vec![Some(7), None, None]);
+    }
+
+    struct MetaRunner {
+        metas: Arc<Mutex<Vec<(usize, BatchMeta)>>>,
+    }
+
+    impl BatchRunnable<u64> for MetaRunner {
+        fn run_batch(&mut self, _: &mut Vec<u64>) {
+            unreachable!();
+        }
+
+        fn run_batch_with_meta(&mut self, ts: &mut Vec<u64>, meta: &BatchMeta) {
+            self.metas.lock().unwrap().push((ts.len(), *meta));
+            ts.clear();
+        }
+    }
+
+    struct SleepRunner {
+        waits: Arc<Mutex<Vec<Duration>>>,
+    }
+
+    impl BatchRunnable<u64> for SleepRunner {
+        fn run_batch(&mut self, _: &mut Vec<u64>) {
+            unreachable!();
+        }
+
+        fn run_batch_with_meta(&mut self, ts: &mut Vec<u64>, meta: &BatchMeta) {
+            self.waits.lock().unwrap().push(meta.wait);
+            for t in ts.drain(..) {
+                thread::sleep(Duration::from_millis(t));
+            }
+        }
+    }
+
+    struct PartialRunner {
+        // at most how many tasks are handled in a batch.
+        limit: usize,
+        records: Arc<Mutex<Vec<(u64, Vec<u64>)>>>,
+    }
+
+    impl ControlledBatchRunnable<u64> for PartialRunner {
+        fn run_batch(&mut self, ts: &mut Vec<u64>, meta: &BatchMeta) {
+            let batch = ts.clone();
+            let count = cmp::min(self.limit, ts.len());
+            self.records.lock().unwrap().push((meta.batch_seq, batch));
+            ts.drain(..count);
+        }
+    }
+
+    #[test]
+    fn test_controlled_batch() {
+        let mut worker = ScopedWorker::new(Worker::new("test-worker-controlled"));
+        for i in 1..7 {
+            worker.schedule(i).unwrap();
+        }
+        let records = Arc::new(Mutex::new(vec![]));
+        let runner = PartialRunner {
+            limit: 2,
+            records: records.clone(),
+        };
+        worker.start_controlled_batch(runner, 4).unwrap();
+        for _ in 0..100 {
+            if worker.stats().handled == 6 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let stats = worker.stats();
+        assert_eq!(stats.pending, 0);
+        assert_eq!(stats.leftovers, 0);
+        assert_eq!(stats.lane_pending, vec![0]);
+        drop(worker);
+        assert_eq!(*records.lock().unwrap(),
+                   vec![(1, vec![1, 2, 3, 4]), (2, vec![3, 4, 5, 6]), (3, vec![5, 6])]);
+    }
+
+    struct StuckRunner;
+
+    impl ControlledBatchRunnable<u64> for StuckRunner {
+        fn run_batch(&mut self, _: &mut Vec<u64>, _: &BatchMeta) {}
+    }
+
+    #[test]
+    fn test_controlled_batch_stuck() {
+        let mut worker = Worker::new("test-worker-controlled-stuck");
+        worker.schedule_all(vec![1, 2, 3]).unwrap();
+        worker.start_controlled_batch(StuckRunner, 2).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        // backs off instead of handing the same batch back at once.
+        let batches = worker.scheduler.shared.batches.load(Ordering::SeqCst);
+        assert!(batches > 1 && batches < 100, "{}", batches);
+
+        // the leftovers and the queued task are dropped on stop.
+        worker.stop_timeout(Duration::from_secs(1)).unwrap();
+        let stats = worker.stats();
+        assert_eq!(stats.handled, 0);
+        assert_eq!(stats.rejected, 3);
+        assert_eq!(stats.pending, 0);
+        assert_eq!(stats.leftovers, 0);
+        assert_eq!(stats.lane_pending, vec![0]);
+    }
+
+    struct GrowRunner;
+
+    impl ControlledBatchRunnable<u64> for GrowRunner {
+        fn run_batch(&mut self, ts: &mut Vec<u64>, _: &BatchMeta) {
+            panic_hook::mute();
+            ts.push(0);
+        }
+    }
+
+    #[test]
+    fn test_controlled_batch_grow() {
+        let mut worker = Worker::new("test-worker-controlled-grow");
+        worker.disable_stall_detection();
+        worker.start_controlled_batch(GrowRunner, 2).unwrap();
+        worker.schedule(1).unwrap();
+        // adding tasks to the batch breaks the contract.
+        assert!(worker.stop().unwrap().join().is_err());
+    }
+
+    #[test]
+    fn test_queue_wait() {
+        let mut worker = ScopedWorker::new(Worker::new("test-worker-wait"));
+        let waits = Arc::new(Mutex::new(vec![]));
+        worker.start_batch(SleepRunner { waits: waits.clone() }, 1).unwrap();
+        worker.schedule(100).unwrap();
+        worker.schedule(0).unwrap();
+        drop(worker);
+
+        let waits = waits.lock().unwrap();
+        assert_eq!(waits.len(), 2);
+        assert!(waits[0] < Duration::from_millis(100));
+        assert!(waits[1] >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_batch_meta() {
+        let mut worker = ScopedWorker::new(Worker::new("test-worker-meta"));
+        let before = Instant::now();
+        worker.schedule(1).unwrap();
+        let after = Instant::now();
+        thread::sleep(Duration::from_millis(100));
+        worker.schedule(2).unwrap();
+        worker.schedule(3).unwrap();
+
+        let metas = Arc::new(Mutex::new(vec![]));
+        worker.start_batch(MetaRunner { metas: metas.clone() }, 2).unwrap();
+        drop(worker);
+
+        let metas = metas.lock().unwrap();
+        assert_eq!(metas.len(), 2);
+        let (count, first) = metas[0];
+        assert_eq!(count, 2);
+        assert_eq!(first.batch_seq, 1);
+        assert!(first.oldest_enqueued_at >= before && first.oldest_enqueued_at <= after);
+        let (count, second) = metas[1];
+        assert_eq!(count, 1);
+        assert_eq!(second.batch_seq, 2);
+        assert!(second.oldest_enqueued_at >= after + Duration::from_millis(100));
+    }
+
+    struct GateRunner {
+        gate: mpsc::Receiver<()>,
+    }
+
+    impl Runnable<u64> for GateRunner {
+        fn run(&mut self, _: u64) {
+            self.gate.recv().unwrap();
+        }
+    }
+
+    fn wait_pending(worker: &Worker<u64>, pending: usize) {
+        for _ in 0..300 {
+            if worker.stats().pending == pending {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("pending never reaches {}: {:?}", pending, worker.stats());
+    }
+
+    #[test]
+    fn test_busy_watermarks() {
+        let mut worker = Worker::new("test-worker-busy");
+        // a worker that is not started is always busy, check the scheduler instead.
+        let scheduler = worker.scheduler();
+        assert!(!scheduler.is_busy());
+        worker.schedule(0).unwrap();
+        assert!(scheduler.is_busy());
+
+        worker.set_busy_watermarks(4, 1);
+        assert!(!scheduler.is_busy());
+        for i in 1..4 {
+            worker.schedule(i).unwrap();
+            assert_eq!(scheduler.is_busy(), i == 3);
+        }
+        worker.schedule(4).unwrap();
+        assert!(scheduler.is_busy());
+
+        let (tx, rx) = mpsc::channel();
+        worker.start(GateRunner { gate: rx }).unwrap();
+        // the first task is dispatched and blocked.
+        wait_pending(&worker, 4);
+        assert!(scheduler.is_busy());
+        for pending in &[3, 2] {
+            tx.send(()).unwrap();
+            wait_pending(&worker, *pending);
+            assert!(scheduler.is_busy());
+        }
+        tx.send(()).unwrap();
+        wait_pending(&worker, 1);
+        assert!(!scheduler.is_busy());
+
+        // no flapping inside the band.
+        worker.schedule(5).unwrap();
+        tx.send(()).unwrap();
+        wait_pending(&worker, 1);
+        assert!(!scheduler.is_busy());
+        worker.schedule(6).unwrap();
+        worker.schedule(7).unwrap();
+        assert!(!scheduler.is_busy());
+        worker.schedule(8).unwrap();
+        assert!(scheduler.is_busy());
+
+        for _ in 0..6 {
+            tx.send(()).unwrap();
+        }
+        worker.stop().unwrap().join().unwrap();
+        assert!(!scheduler.is_busy());
+    }
+
+    #[test]
+    fn test_busy_race() {
+        let mut worker = ScopedWorker::new(Worker::new("test-worker-busy-race"));
+        let count = Arc::new(AtomicUsize::new(0));
+        worker.start_batch(BatchRunner { count: count.clone() }, 1).unwrap();
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let scheduler = worker.scheduler();
+                thread::spawn(move || for _ in 0..10000 {
+                    scheduler.schedule(0).unwrap();
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        wait_pending(&worker, 0);
+        // the state never lags behind the pending count once the worker is idle.
+        assert!(!worker.is_busy());
+    }
+
+    #[derive(Clone)]
+    struct NameRunner {
+        names: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Runnable<u64> for NameRunner {
+        fn run(&mut self, t: u64) {
+            // strip the tag inherited from the test thread.
+            let name = thread::current().name().unwrap().split("::").next().unwrap().to_owned();
+            self.names.lock().unwrap().push(name);
+            thread::sleep(Duration::from_millis(t));
+        }
+    }
+
+    #[test]
+    fn test_pool_thread_name() {
+        assert_eq!(pool_thread_name("pool", 3, 4), "pool-3");
+        assert_eq!(pool_thread_name("pool", 3, 12), "pool-03");
+        assert_eq!(pool_thread_name("pool", 10, 12), "pool-10");
+        assert_eq!(pool_thread_name("a-very-long-worker-name", 5, 8), "a-very-long-w-5");
+        assert_eq!(pool_thread_name("a-very-long-worker-name", 11, 12), "a-very-long--11");
+    }
+
+    #[test]
+    fn test_pool() {
+        let mut worker = ScopedWorker::new(Worker::new("test-pool"));
+        assert_eq!(worker.thread_count(), 0);
+        let names = Arc::new(Mutex::new(vec![]));
+        worker.start_pool(NameRunner { names: names.clone() }, 4).unwrap();
+        assert_eq!(worker.thread_count(), 4);
+        assert_eq!(worker.stats().threads, 4);
+        // every task blocks its thread long enough for the others to pick up the rest.
+        for _ in 0..8 {
+            worker.schedule(200).unwrap();
+        }
+        drop(worker);
+
+        let mut names = names.lock().unwrap().clone();
+        assert_eq!(names.len(), 8);
+        names.sort();
+        names.dedup();
+        assert_eq!(names, vec!["test-pool-0", "test-pool-1", "test-pool-2", "test-pool-3"]);
+    }
+
+    #[test]
+    fn test_current_task() {
+        let mut worker = ScopedWorker::new(Worker::new("test-worker-current"));
+        let count = Arc::new(AtomicUsize::new(0));
+        worker.start(CountRunner { count: count.clone() }).unwrap();
+        assert_eq!(worker.current_task(), None);
+
+        worker.schedule(1).unwrap();
+        // the runner sleeps for 10ms after counting the task.
+        while count.load(Ordering::SeqCst) == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(worker.current_task(), Some("1 [seq=1]".to_owned()));
+        assert_eq!(worker.stats().current_task, Some("1 [seq=1]".to_owned()));
+
+        for _ in 0..100 {
+            if worker.current_task().is_none() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(worker.current_task(), None);
+    }
+
+    struct ClassTask(u64);
+
+    impl Display for ClassTask {
+        fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+            write!(f, "class task {}", self.0)
+        }
+    }
+
+    impl TaskClass for ClassTask {
+        fn class(&self) -> usize {
+            self.0 as usize % 2
+        }
+
+        fn class_count() -> usize {
+            2
+        }
+    }
+
+    impl Runnable<ClassTask> for CountRunner {
+        fn run(&mut self, t: ClassTask) {
+            self.count.fetch_add(t.0 as usize, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_class_limits() {
+        let mut worker = ScopedWorker::new(Worker::new("test-worker-class"));
+        worker.set_class_limits(&[2, 100]);
+        worker.schedule(ClassTask(0)).unwrap();
+        worker.schedule(ClassTask(2)).unwrap();
+        match worker.schedule(ClassTask(4)) {
+            Err(ScheduleError::ClassFull(ClassTask(4))) => {}
+            res => panic!("expect class full, got {:?}", res),
+        }
+        // other classes are not affected.
+        for i in 0..10 {
+            worker.schedule(ClassTask(i * 2 + 1)).unwrap();
+        }
+        assert_eq!(worker.stats().class_pending, vec![2, 10]);
+        assert_eq!(worker.stats().pending, 12);
+
+        let count = Arc::new(AtomicUsize::new(0));
+        worker.start(CountRunner { count: count.clone() }).unwrap();
+        for _ in 0..100 {
+            if worker.stats().handled == 12 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(worker.stats().class_pending, vec![0, 0]);
+        worker.schedule(ClassTask(4)).unwrap();
+        let scheduler = worker.scheduler();
+        drop(worker);
+        assert_eq!(count.load(Ordering::SeqCst), 2 + 100 + 4);
+        assert_eq!(scheduler.stats().class_pending, vec![0, 0]);
+    }
+
+    #[test]
+    fn test_rejected_count() {
+        let mut worker = Worker::new("test-worker-rejected");
+        worker.start(CountRunner { count: Arc::new(AtomicUsize::new(0)) }).unwrap();
+        worker.schedule(1).unwrap();
+        worker.stop().unwrap().join().unwrap();
+        assert_eq!(worker.scheduler().rejected_count(), 0);
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let scheduler = worker.scheduler();
+                thread::spawn(move || (0..100).filter(|&i| scheduler.schedule(i).is_err()).count())
+            })
+            .collect();
+        let failed: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+        assert_eq!(failed, 400);
+        assert_eq!(worker.scheduler().rejected_count(), failed);
+        assert_eq!(worker.stats().rejected, failed);
+        assert_eq!(worker.stats().pending, 0);
+    }
+
+    #[test]
+    fn test_schedule_all() {
+        let mut worker = ScopedWorker::new(Worker::new("test-worker-schedule-all"));
+        worker.set_class_limits(&[2, 100]);
+        worker.schedule_all(vec![ClassTask(0), ClassTask(1), ClassTask(3)]).unwrap();
+        worker.schedule_all(vec![]).unwrap();
+        // all or nothing.
+        match worker.schedule_all(vec![ClassTask(5), ClassTask(2), ClassTask(4)]) {
+            Err(ScheduleError::ClassFull(ts)) => {
+                assert_eq!(ts.iter().map(|t| t.0).collect::<Vec<_>>(), vec![5, 2, 4]);
+            }
+            res => panic!("expect class full, got {:?}", res),
+        }
+        let stats = worker.stats();
+        assert_eq!(stats.pending, 3);
+        assert_eq!(stats.class_pending, vec![1, 2]);
+        assert_eq!(stats.last_scheduled_seq, 3);
+        worker.schedule_all(vec![ClassTask(5), ClassTask(2)]).unwrap();
+
+        let count = Arc::new(AtomicUsize::new(0));
+        worker.start(CountRunner { count: count.clone() }).unwrap();
+        let scheduler = worker.scheduler();
+        drop(worker);
+        assert_eq!(count.load(Ordering::SeqCst), 11);
+        let stats = scheduler.stats();
+        assert_eq!(stats.pending, 0);
+        assert_eq!(stats.class_pending, vec![0, 0]);
+        assert_eq!(stats.last_dispatched_seq, 5);
+    }
+
+    #[test]
+    fn test_schedule_all_stopped() {
+        let worker: Worker<u64> = Worker::new("test-worker-schedule-all-stopped");
+        let scheduler = worker.scheduler();
+        let s = scheduler.clone();
+        let h = thread::spawn(move || {
+            let mut batches = 0;
+            loop {
+                match s.schedule_all((0..1000).collect()) {
+                    Ok(()) => batches += 1,
+                    Err(ScheduleError::Stopped(unsent)) => {
+                        // the unsent tasks are always the tail of the batch.
+                        let first = unsent[0];
+                        assert_eq!(unsent, (first..1000).collect::<Vec<_>>());
+                        return (batches, unsent.len());
+                    }
+                    Err(e) => panic!("unexpected error {:?}", e),
+                }
+            }
+        });
+        thread::sleep(Duration::from_millis(10));
+        // dropping the receiver while tasks are being sent.
+        drop(worker);
+        let (batches, unsent) = h.join().unwrap();
+        assert!(batches > 0);
+        assert_eq!(scheduler.rejected_count(), unsent);
+
+        match scheduler.schedule_all(vec![1, 2, 3]) {
+            Err(ScheduleError::Stopped(unsent)) => assert_eq!(unsent, vec![1, 2, 3]),
+            res => panic!("expect stopped, got {:?}", res),
+        }
+        assert_eq!(scheduler.rejected_count(), unsent + 3);
+    }
+
+    #[test]
+    fn test_sampled_log() {
+        let mut worker = Worker::new("test-worker-sampled-log");
+        for i in 0..10 {
+            worker.schedule(i).unwrap();
+        }
+        assert_eq!(worker.scheduler.shared.sampled_logs.load(Ordering::SeqCst), 0);
+
+        worker.set_log_sample_interval(10);
+        for i in 0..90 {
+            worker.schedule(i).unwrap();
+        }
+        let sampled = worker.scheduler.shared.sampled_logs.load(Ordering::SeqCst);
+        assert_eq!(sampled, 9);
+        worker.schedule_all((0..25).collect()).unwrap();
+        let sampled = worker.scheduler.shared.sampled_logs.load(Ordering::SeqCst);
+        assert_eq!(sampled, 10);
+
+        worker.start_batch(MetaRunner { metas: Arc::new(Mutex::new(vec![])) }, 4).unwrap();
+        worker.stop().unwrap().join().unwrap();
+        let sampled = worker.scheduler.shared.sampled_logs.load(Ordering::SeqCst) - sampled;
+        // a batch of at most 4 tasks can't cover 2 multiples of 10.
+        assert_eq!(sampled, 12);
+    }
+
+    #[test]
+    fn test_last_active() {
+        let mut worker = ScopedWorker::new(Worker::new("test-worker-active"));
+        worker.set_heartbeat_interval(Some(Duration::from_millis(10)));
+        assert_eq!(worker.last_active(), None);
+        let (tx, rx) = mpsc::channel();
+        worker.start(GateRunner { gate: rx }).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        let t1 = worker.last_active().unwrap();
+        // advances on idle ticks.
+        thread::sleep(Duration::from_millis(100));
+        let t2 = worker.stats().last_active.unwrap();
+        assert!(t2 > t1);
+
+        // freezes when the runner is blocked.
+        worker.schedule(1).unwrap();
+        for _ in 0..100 {
+            if worker.current_task().is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let t3 = worker.last_active().unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(worker.last_active().unwrap(), t3);
+
+        tx.send(()).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert!(worker.last_active().unwrap() > t3);
+    }
+
+    #[test]
+    fn test_stop_timeout() {
+        let mut worker = Worker::new("test-worker-stop-timeout");
+        let count = Arc::new(AtomicUsize::new(0));
+        worker.start_batch(BatchRunner { count: count.clone() }, 1).unwrap();
+        worker.schedule(500).unwrap();
+        while count.load(Ordering::SeqCst) == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let err = worker.stop_timeout(Duration::from_millis(50)).unwrap_err();
+        // tasks of batch workers are identified by sequence numbers only.
+        assert!(format!("{}", err).contains("[seq=1]"), "{}", err);
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let mut worker = ScopedWorker::new(Worker::new("test-worker-rate-limit"));
+        worker.set_max_tasks_per_sec(20.0, 5);
+        let count = Arc::new(AtomicUsize::new(0));
+        worker.start_batch(BatchRunner { count: count.clone() }, 100).unwrap();
+        let start = Instant::now();
+        worker.schedule_all(vec![1; 15]).unwrap();
+        // the burst is dispatched at once, the others are throttled.
+        thread::sleep(Duration::from_millis(50));
+        assert!(count.load(Ordering::SeqCst) < 15);
+        assert!(worker.stats().pending > 0);
+        assert!(worker.is_busy());
+        while count.load(Ordering::SeqCst) < 15 {
+            assert!(start.elapsed() < Duration::from_secs(3));
+            thread::sleep(Duration::from_millis(5));
+        }
+        // 10 tasks out of the burst take 500ms at 20 tasks/s.
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(400), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(1500), "{:?}", elapsed);
+        for _ in 0..100 {
+            if !worker.is_busy() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!worker.is_busy());
+    }
+
+    #[test]
+    fn test_rate_limit_stop() {
+        let mut worker = Worker::new("test-worker-rate-limit-stop");
+        worker.set_max_tasks_per_sec(1.0, 1);
+        let count = Arc::new(AtomicUsize::new(0));
+        worker.start_batch(BatchRunner { count: count.clone() }, 1).unwrap();
+        worker.schedule_all(vec![1; 10]).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        // the throttled tasks are dropped instead of being dispatched at once.
+        worker.stop_timeout(Duration::from_millis(500)).unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        let stats = worker.stats();
+        assert_eq!(stats.rejected, 9);
+        assert_eq!(stats.pending, 0);
+        assert_eq!(stats.lane_pending, vec![0]);
+    }
+
+    // reported workers and why they are reported.
+    type Stalled = Arc<Mutex<Vec<(String, StallKind)>>>;
+
+    fn stall_detector() -> (StallDetector, Stalled) {
+        let stalled = Arc::new(Mutex::new(vec![]));
+        let stalled2 = stalled.clone();
+        let detector = StallDetector::new(Duration::from_millis(100), move |name: &str, kind| {
+            stalled2.lock().unwrap().push((name.to_owned(), kind));
+        });
+        (detector, stalled)
+    }
+
+    fn reported(stalled: &Stalled, name: &str) -> Vec<StallKind> {
+        stalled.lock().unwrap().iter().filter(|&&(ref n, _)| n == name).map(|&(_, k)| k).collect()
+    }
+
+    #[test]
+    fn test_stall_detector() {
+        let mut worker = ScopedWorker::new(Worker::new("test-worker-stalled"));
+        let count = Arc::new(AtomicUsize::new(0));
+        worker.start_batch(BatchRunner { count: count.clone() }, 1).unwrap();
+        let mut idle = ScopedWorker::new(Worker::new("test-worker-stall-ignored"));
+        idle.disable_stall_detection();
+        idle.start_batch(BatchRunner { count: count.clone() }, 1).unwrap();
+
+        let (detector, stalled) = stall_detector();
+        // the first task blocks the worker, so the second one keeps pending.
+        for _ in 0..2 {
+            worker.schedule(1000).unwrap();
+            idle.schedule(1000).unwrap();
+        }
+        thread::sleep(Duration::from_millis(500));
+        drop(detector);
+
+        let kinds = reported(&stalled, "test-worker-stalled");
+        assert!(!kinds.is_empty());
+        assert!(kinds.iter().all(|&k| k == StallKind::Blocked), "{:?}", kinds);
+        assert!(reported(&stalled, "test-worker-stall-ignored").is_empty());
+    }
+
+    struct PanicRunner;
+
+    impl Runnable<u64> for PanicRunner {
+        fn run(&mut self, _: u64) {
+            panic_hook::mute();
+            panic!("runner panics on purpose");
+        }
+    }
+
+    #[test]
+    fn test_stall_detector_dead() {
+        let mut worker = Worker::new("test-worker-dead");
+        worker.schedule(1).unwrap();
+        worker.schedule(2).unwrap();
+        worker.start(PanicRunner).unwrap();
+
+        let (detector, stalled) = stall_detector();
+        thread::sleep(Duration::from_millis(300));
+        // the exited thread doesn't leave its task behind.
+        assert_eq!(worker.current_task(), None);
+        let kinds = reported(&stalled, "test-worker-dead");
+        assert!(!kinds.is_empty());
+        assert!(kinds.iter().all(|&k| k == StallKind::Dead), "{:?}", kinds);
+
+        // a stopped worker is not reported any more.
+        assert!(worker.stop().unwrap().join().is_err());
+        thread::sleep(Duration::from_millis(100));
+        stalled.lock().unwrap().clear();
+        thread::sleep(Duration::from_millis(300));
+        drop(detector);
+        assert!(reported(&stalled, "test-worker-dead").is_empty());
+    }
+
+    #[test]
+    fn test_worker_id() {
+        let worker = Worker::<u64>::new("test-worker-id");
+        let id = worker.scheduler.shared.id;
+        drop(worker);
+        // even if the new worker reuses the address of the dropped one.
+        let worker = Worker::<u64>::new("test-worker-id");
+        assert!(worker.scheduler.shared.id != id);
+    }
+
+    #[test]
+    fn test_scoped_worker() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let count2 = count.clone();
+        let worker = Worker::new("test-worker-scoped");
+        let scheduler = worker.scheduler();
+        let res = recover_safe!(move || {
+            let mut worker = ScopedWorker::new(worker);
+            worker.start(CountRunner { count: count2 }).unwrap();
+            worker.schedule(1).unwrap();
+            panic!("panic before stopping worker");
+        });
+        assert!(res.is_err());
+        // the worker thread has exited once the guard is dropped.
+        assert_eq!(scheduler.shared.threads.load(Ordering::SeqCst), 1);
+        assert_eq!(scheduler.shared.running.load(Ordering::SeqCst), 0);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        let mut worker = ScopedWorker::new(Worker::new("test-worker-scoped-inner"));
+        worker.start(CountRunner { count: count.clone() }).unwrap();
+        let mut worker = worker.into_inner();
+        worker.schedule(1).unwrap();
+        // the thread is left running after `into_inner`, it only exits on stop.
+        assert_eq!(worker.scheduler().shared.running.load(Ordering::SeqCst), 1);
+        worker.stop().unwrap().join().unwrap();
+        assert_eq!(worker.scheduler().shared.running.load(Ordering::SeqCst), 0);
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+    }
 }
@@ -0,0 +1,257 @@
+// Copyright 2016 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::error::Error;
+use std::fmt::{self, Formatter, Display};
+use std::io;
+use std::time::Duration;
+
+use super::{Runnable, Scheduler, TimerHandle, Worker};
+
+/// A task with the count of attempts to run it.
+pub struct Retryable<T> {
+    task: T,
+    attempt: u32,
+}
+
+impl<T> Retryable<T> {
+    pub fn new(task: T) -> Retryable<T> {
+        Retryable {
+            task: task,
+            attempt: 1,
+        }
+    }
+
+    /// The current attempt, starts from 1.
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    pub fn into_inner(self) -> T {
+        self.task
+    }
+}
+
+impl<T: Display> Display for Retryable<T> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{} [attempt={}]", self.task, self.attempt)
+    }
+}
+
+#[derive(Debug)]
+pub enum RetryError {
+    /// Run the task again after the duration.
+    Retry(Duration),
+    /// Give up the task without retrying.
+    Abort(Box<Error + Send + Sync>),
+}
+
+pub trait RetryableRunnable<T> {
+    /// Run the task, it will be run again later if `RetryError::Retry` is returned.
+    fn run(&mut self, task: &mut T) -> Result<(), RetryError>;
+
+    /// Called when the task is aborted, still fails after the max attempts, or
+    /// can't be retried because the timer is stopped.
+    fn on_give_up(&mut self, task: T, err: RetryError);
+}
+
+struct RetryRunner<R, T> {
+    runner: R,
+    timer: TimerHandle,
+    max_attempts: u32,
+    scheduler: Option<Scheduler<Retryable<T>>>,
+}
+
+impl<R, T> Runnable<Retryable<T>> for RetryRunner<R, T>
+    where R: RetryableRunnable<T>,
+          T: Display + Send + 'static
+{
+    fn run(&mut self, mut t: Retryable<T>) {
+        let err = match self.runner.run(&mut t.task) {
+            Ok(()) => return,
+            Err(e) => e,
+        };
+        let delay = match err {
+            RetryError::Retry(delay) if t.attempt < self.max_attempts => delay,
+            e => {
+                warn!("give up {}: {:?}", t, e);
+                self.runner.on_give_up(t.task, e);
+                return;
+            }
+        };
+        debug!("retry {} after {:?}", t, delay);
+        t.attempt += 1;
+        let scheduler = self.scheduler.clone().unwrap();
+        if let Err(e) = self.timer.schedule_after(delay, scheduler, t) {
+            let reason = format!("failed to retry: {}", e);
+            let t = e.into_inner();
+            error!("give up {}: {}", t, reason);
+            self.runner.on_give_up(t.task, RetryError::Abort(box_err!(reason)));
+        }
+    }
+
+    fn on_start(&mut self, scheduler: Scheduler<Retryable<T>>) {
+        self.scheduler = Some(scheduler);
+    }
+}
+
+impl<T: Display + Send + 'static> Worker<Retryable<T>> {
+    /// Start the worker with a runner whose tasks can be retried, a task is
+    /// run at most `max_attempts` times.
+    ///
+    /// Retries are delayed by `timer`.
+    pub fn start_retryable<R>(&mut self,
+                              runner: R,
+                              timer: TimerHandle,
+                              max_attempts: u32)
+                              -> Result<(), io::Error>
+        where R: RetryableRunnable<T> + Send + 'static
+    {
+        assert!(max_attempts > 0);
+        self.start(RetryRunner {
+            runner: runner,
+            timer: timer,
+            max_attempts: max_attempts,
+            scheduler: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc::{self, Sender};
+    use std::time::Duration;
+
+    use super::*;
+    use super::super::{TimerWorker, Worker, current_trace_id};
+
+    struct FlakyRunner {
+        // every attempt fails until the `succeed_at`th one.
+        succeed_at: u32,
+        attempts: u32,
+        tx: Sender<(u64, u32, bool)>,
+    }
+
+    impl RetryableRunnable<u64> for FlakyRunner {
+        fn run(&mut self, task: &mut u64) -> Result<(), RetryError> {
+            self.attempts += 1;
+            if self.attempts < self.succeed_at {
+                // the task is kept between attempts.
+                *task += 1;
+                return Err(RetryError::Retry(Duration::from_millis(10)));
+            }
+            self.tx.send((*task, self.attempts, true)).unwrap();
+            Ok(())
+        }
+
+        fn on_give_up(&mut self, task: u64, err: RetryError) {
+            match err {
+                RetryError::Retry(_) => {}
+                e => panic!("unexpected error {:?}", e),
+            }
+            self.tx.send((task, self.attempts, false)).unwrap();
+        }
+    }
+
+    fn run_flaky(succeed_at: u32, max_attempts: u32) -> (u64, u32, bool) {
+        let mut timer = TimerWorker::new("test-retry-timer");
+        timer.start().unwrap();
+        let mut worker = Worker::new("test-retry");
+        let (tx, rx) = mpsc::channel();
+        let runner = FlakyRunner {
+            succeed_at: succeed_at,
+            attempts: 0,
+            tx: tx,
+        };
+        worker.start_retryable(runner, timer.handle(), max_attempts).unwrap();
+        worker.schedule(Retryable::new(0)).unwrap();
+        let res = rx.recv_timeout(Duration::from_secs(3)).unwrap();
+        worker.stop().unwrap().join().unwrap();
+        timer.stop().unwrap().join().unwrap();
+        assert!(rx.try_recv().is_err());
+        res
+    }
+
+    #[test]
+    fn test_retry_success() {
+        assert_eq!(run_flaky(1, 3), (0, 1, true));
+        assert_eq!(run_flaky(3, 3), (2, 3, true));
+    }
+
+    #[test]
+    fn test_retry_exhausted() {
+        assert_eq!(run_flaky(10, 1), (1, 1, false));
+        assert_eq!(run_flaky(10, 4), (4, 4, false));
+    }
+
+    // reports the trace ID of every attempt, and the error when giving up.
+    struct RetryForever {
+        tx: Sender<Result<Option<u64>, String>>,
+    }
+
+    impl RetryableRunnable<u64> for RetryForever {
+        fn run(&mut self, _: &mut u64) -> Result<(), RetryError> {
+            self.tx.send(Ok(current_trace_id())).unwrap();
+            Err(RetryError::Retry(Duration::from_millis(10)))
+        }
+
+        fn on_give_up(&mut self, _: u64, err: RetryError) {
+            self.tx.send(Err(format!("{:?}", err))).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_retry_traced() {
+        let mut timer = TimerWorker::new("test-retry-timer");
+        timer.start().unwrap();
+        let mut worker = Worker::new("test-retry-traced");
+        let (tx, rx) = mpsc::channel();
+        worker.start_retryable(RetryForever { tx: tx }, timer.handle(), 3).unwrap();
+        worker.schedule_traced(Retryable::new(0), 7).unwrap();
+        let timeout = Duration::from_secs(3);
+        for _ in 0..3 {
+            assert_eq!(rx.recv_timeout(timeout).unwrap(), Ok(Some(7)));
+        }
+        assert!(rx.recv_timeout(timeout).unwrap().is_err());
+        worker.stop().unwrap().join().unwrap();
+        timer.stop().unwrap().join().unwrap();
+    }
+
+    #[test]
+    fn test_retry_timer_stopped() {
+        let mut timer = TimerWorker::new("test-retry-timer");
+        timer.start().unwrap();
+        let handle = timer.handle();
+        timer.stop().unwrap().join().unwrap();
+        let mut worker = Worker::new("test-retry-timer-stopped");
+        let (tx, rx) = mpsc::channel();
+        worker.start_retryable(RetryForever { tx: tx }, handle, 3).unwrap();
+        worker.schedule(Retryable::new(0)).unwrap();
+        let timeout = Duration::from_secs(3);
+        assert_eq!(rx.recv_timeout(timeout).unwrap(), Ok(None));
+        // the task is given up instead of being dropped silently.
+        let err = rx.recv_timeout(timeout).unwrap().unwrap_err();
+        assert!(err.contains("failed to retry"), "{}", err);
+        worker.stop().unwrap().join().unwrap();
+    }
+
+    #[test]
+    fn test_retryable_display() {
+        let mut t = Retryable::new(5);
+        assert_eq!(format!("{}", t), "5 [attempt=1]");
+        t.attempt += 1;
+        assert_eq!(t.attempt(), 2);
+        assert_eq!(format!("{}", t), "5 [attempt=2]");
+        assert_eq!(t.into_inner(), 5);
+    }
+}
@@ -0,0 +1,129 @@
+// Copyright 2016 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Sender, RecvTimeoutError};
+use std::thread::{JoinHandle, Builder};
+use std::time::Duration;
+
+use super::registered_progress;
+
+const DEFAULT_CHECK_INTERVAL_SECS: u64 = 30;
+
+/// Why a worker is reported by `StallDetector`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StallKind {
+    /// Some threads of the worker exited although it's not stopped.
+    Dead,
+    /// The worker has pending tasks but handled nothing since last check.
+    Blocked,
+}
+
+/// `StallDetector` checks all the started workers periodically, and reports
+/// the ones whose threads exited unexpectedly, or that have pending tasks but
+/// handled nothing since last check.
+pub struct StallDetector {
+    tx: Sender<bool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl StallDetector {
+    pub fn new<F>(interval: Duration, on_stalled: F) -> StallDetector
+        where F: Fn(&str, StallKind) + Send + 'static
+    {
+        let (tx, rx) = mpsc::channel();
+        let h = Builder::new()
+            .name(thd_name!("stall-detector"))
+            .spawn(move || {
+                // worker id -> count of handled tasks at last check.
+                let mut last = HashMap::new();
+                while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(interval) {
+                    last = check(&last, interval, &on_stalled);
+                }
+            })
+            .unwrap();
+
+        StallDetector {
+            tx: tx,
+            handle: Some(h),
+        }
+    }
+}
+
+fn check<F: Fn(&str, StallKind)>(last: &HashMap<usize, usize>,
+                                 interval: Duration,
+                                 on_stalled: &F)
+                                 -> HashMap<usize, usize> {
+    let mut sampled = HashMap::with_capacity(last.len());
+    for p in registered_progress() {
+        if p.stopped {
+            continue;
+        }
+        if p.running < p.threads {
+            // threads only exit after the worker is stopped, unless they panic.
+            error!("worker {} seems dead: {} of {} threads exited, {} pending",
+                   p.name,
+                   p.threads - p.running,
+                   p.threads,
+                   p.pending);
+            on_stalled(&p.name, StallKind::Dead);
+            continue;
+        }
+        if let Some(&handled) = last.get(&p.id) {
+            if p.pending > 0 && p.handled == handled {
+                let inactive = p.last_active.map_or(true, |t| t.elapsed() >= interval);
+                if p.current_task.is_none() && inactive {
+                    // not handling any task, yet not fetching tasks either.
+                    error!("worker {} stalled: {} pending, no activity in last {:?}",
+                           p.name,
+                           p.pending,
+                           interval);
+                } else {
+                    error!("worker {} stalled: {} pending, 0 handled in last {:?}, current \
+                            task: {:?}",
+                           p.name,
+                           p.pending,
+                           interval,
+                           p.current_task);
+                }
+                on_stalled(&p.name, StallKind::Blocked);
+            }
+        }
+        sampled.insert(p.id, p.handled);
+    }
+    sampled
+}
+
+impl Default for StallDetector {
+    fn default() -> StallDetector {
+        StallDetector::new(Duration::from_secs(DEFAULT_CHECK_INTERVAL_SECS), |_, _| {})
+    }
+}
+
+impl Drop for StallDetector {
+    fn drop(&mut self) {
+        let h = match self.handle.take() {
+            Some(h) => h,
+            None => return,
+        };
+
+        if let Err(e) = self.tx.send(true) {
+            error!("send quit message for stall detector failed {:?}", e);
+            return;
+        }
+
+        if let Err(e) = h.join() {
+            error!("join stall detector failed {:?}", e);
+        }
+    }
+}
@@ -0,0 +1,183 @@
+// Copyright 2016 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Helpers to test code that schedules tasks to other workers.
+
+use std::fmt::Display;
+use std::mem;
+use std::sync::{Arc, Mutex, Condvar};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{Runnable, Scheduler, ScopedWorker, Worker};
+
+const FLUSH_TIMEOUT_SECS: u64 = 3;
+const FLUSH_CHECK_INTERVAL_MILLIS: u64 = 1;
+
+struct Recorder<T> {
+    tasks: Mutex<Vec<T>>,
+    cond: Condvar,
+}
+
+struct RecordRunner<T> {
+    recorder: Arc<Recorder<T>>,
+}
+
+impl<T: Display> Runnable<T> for RecordRunner<T> {
+    fn run(&mut self, t: T) {
+        self.recorder.tasks.lock().unwrap().push(t);
+        self.recorder.cond.notify_all();
+    }
+}
+
+/// Records all the tasks scheduled by the scheduler returned from `capture`.
+///
+/// The tasks are recorded by a real worker, so the scheduler behaves the same
+/// as in production, e.g. scheduling fails after the capture is stopped or dropped.
+pub struct TaskCapture<T: Display + Send + 'static> {
+    worker: ScopedWorker<T>,
+    recorder: Arc<Recorder<T>>,
+}
+
+/// Create a scheduler whose tasks are recorded by the returned `TaskCapture`
+/// instead of being run.
+pub fn capture<T: Display + Send + 'static>() -> (Scheduler<T>, TaskCapture<T>) {
+    let recorder = Arc::new(Recorder {
+        tasks: Mutex::new(vec![]),
+        cond: Condvar::new(),
+    });
+    let mut worker = Worker::new("task-capture");
+    worker.disable_stall_detection();
+    worker.start(RecordRunner { recorder: recorder.clone() }).unwrap();
+    let scheduler = worker.scheduler();
+    let capture = TaskCapture {
+        worker: ScopedWorker::new(worker),
+        recorder: recorder,
+    };
+    (scheduler, capture)
+}
+
+impl<T: Display + Send + 'static> TaskCapture<T> {
+    // wait until all the tasks scheduled so far are recorded.
+    fn flush(&self) {
+        let start = Instant::now();
+        loop {
+            let stats = self.worker.stats();
+            if stats.handled + stats.rejected >= stats.last_scheduled_seq as usize {
+                return;
+            }
+            if start.elapsed() >= Duration::from_secs(FLUSH_TIMEOUT_SECS) {
+                panic!("{} tasks are still not recorded after {}s",
+                       stats.pending,
+                       FLUSH_TIMEOUT_SECS);
+            }
+            thread::sleep(Duration::from_millis(FLUSH_CHECK_INTERVAL_MILLIS));
+        }
+    }
+
+    /// Take all the tasks scheduled so far in order.
+    pub fn take_all(&self) -> Vec<T> {
+        self.flush();
+        let mut tasks = self.recorder.tasks.lock().unwrap();
+        mem::replace(&mut *tasks, vec![])
+    }
+
+    /// Wait until at least `n` tasks are recorded and take all of them.
+    ///
+    /// Panics if there are still less than `n` tasks after `timeout`.
+    pub fn wait_for(&self, n: usize, timeout: Duration) -> Vec<T> {
+        let start = Instant::now();
+        let mut tasks = self.recorder.tasks.lock().unwrap();
+        while tasks.len() < n {
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                panic!("expect {} tasks, but only got {} in {:?}",
+                       n,
+                       tasks.len(),
+                       timeout);
+            }
+            tasks = self.recorder.cond.wait_timeout(tasks, timeout - elapsed).unwrap().0;
+        }
+        mem::replace(&mut *tasks, vec![])
+    }
+
+    /// Assert that no task is scheduled since the last take.
+    pub fn assert_empty(&self) {
+        self.flush();
+        let tasks = self.recorder.tasks.lock().unwrap();
+        if !tasks.is_empty() {
+            let labels: Vec<_> = tasks.iter().map(|t| format!("{}", t)).collect();
+            panic!("expect no task, but got [{}]", labels.join(", "));
+        }
+    }
+
+    /// Stop recording, all the later scheduling will fail.
+    pub fn stop(&mut self) {
+        self.worker.stop_timeout(Duration::from_secs(FLUSH_TIMEOUT_SECS)).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use super::super::ScheduleError;
+
+    #[test]
+    fn test_capture() {
+        let (scheduler, capture) = capture();
+        capture.assert_empty();
+        for i in 0..10u64 {
+            scheduler.schedule(i).unwrap();
+        }
+        assert_eq!(capture.take_all(), (0..10).collect::<Vec<_>>());
+        capture.assert_empty();
+
+        let s = scheduler.clone();
+        let h = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            s.schedule_all(vec![10, 11]).unwrap();
+        });
+        assert_eq!(capture.wait_for(2, Duration::from_secs(3)), vec![10, 11]);
+        h.join().unwrap();
+        capture.assert_empty();
+    }
+
+    #[test]
+    fn test_capture_unexpected() {
+        let (scheduler, capture) = capture();
+        scheduler.schedule(1u64).unwrap();
+        let res = recover_safe!(|| capture.assert_empty());
+        assert!(res.is_err());
+        let res = recover_safe!(|| capture.wait_for(2, Duration::from_millis(50)));
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_capture_stopped() {
+        let (scheduler, mut capture) = capture();
+        scheduler.schedule(1u64).unwrap();
+        capture.stop();
+        match scheduler.schedule(2) {
+            Err(ScheduleError::Stopped(2)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert_eq!(capture.take_all(), vec![1]);
+
+        let (scheduler, capture) = capture();
+        drop(capture);
+        assert!(scheduler.schedule(1u64).is_err());
+    }
+}
@@ -0,0 +1,422 @@
+// Copyright 2016 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::{BinaryHeap, HashMap};
+use std::fmt::{self, Formatter, Display};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use super::{Runnable, Scheduler, ScheduleError, Worker, current_trace_id};
+
+/// Identifies a timer scheduled by `TimerHandle`, it can be used to cancel
+/// or reschedule the timer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TimerToken(u64);
+
+/// Called when the timer fires, returns false if it should not fire again.
+type Callback = Box<FnMut() -> bool + Send>;
+
+enum TimerTask {
+    Schedule {
+        token: u64,
+        deadline: Instant,
+        interval: Option<Duration>,
+        callback: Callback,
+    },
+    Reschedule { token: u64, deadline: Instant },
+    Cancel(u64),
+}
+
+impl Display for TimerTask {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            TimerTask::Schedule { token, interval, .. } => {
+                write!(f, "schedule timer {} [interval {:?}]", token, interval)
+            }
+            TimerTask::Reschedule { token, .. } => write!(f, "reschedule timer {}", token),
+            TimerTask::Cancel(token) => write!(f, "cancel timer {}", token),
+        }
+    }
+}
+
+struct Entry {
+    deadline: Instant,
+    interval: Option<Duration>,
+    callback: Callback,
+}
+
+/// An item of the deadline heap, the earliest deadline is the greatest.
+#[derive(PartialEq, Eq)]
+struct Deadline {
+    at: Instant,
+    token: u64,
+}
+
+impl Ord for Deadline {
+    fn cmp(&self, other: &Deadline) -> CmpOrdering {
+        (other.at, other.token).cmp(&(self.at, self.token))
+    }
+}
+
+impl PartialOrd for Deadline {
+    fn partial_cmp(&self, other: &Deadline) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+struct Runner {
+    timers: HashMap<u64, Entry>,
+    // may contain stale items of cancelled or rescheduled timers, they are
+    // skipped when popped.
+    deadlines: BinaryHeap<Deadline>,
+}
+
+impl Runner {
+    fn new() -> Runner {
+        Runner {
+            timers: HashMap::new(),
+            deadlines: BinaryHeap::new(),
+        }
+    }
+
+    fn fire_due(&mut self) {
+        let now = Instant::now();
+        loop {
+            let (at, token) = match self.deadlines.peek() {
+                Some(d) if d.at <= now => (d.at, d.token),
+                _ => return,
+            };
+            self.deadlines.pop();
+            let keep = match self.timers.get_mut(&token) {
+                Some(ref mut e) if e.deadline == at => {
+                    match e.interval {
+                        Some(interval) if (e.callback)() => {
+                            e.deadline = at + interval;
+                            if e.deadline <= now {
+                                // fall behind, don't try to catch up.
+                                e.deadline = now + interval;
+                            }
+                            self.deadlines.push(Deadline {
+                                at: e.deadline,
+                                token: token,
+                            });
+                            true
+                        }
+                        Some(_) => false,
+                        None => {
+                            (e.callback)();
+                            false
+                        }
+                    }
+                }
+                _ => continue,
+            };
+            if !keep {
+                self.timers.remove(&token);
+            }
+        }
+    }
+}
+
+impl Runnable<TimerTask> for Runner {
+    fn run(&mut self, task: TimerTask) {
+        match task {
+            TimerTask::Schedule { token, deadline, interval, callback } => {
+                self.timers.insert(token,
+                                   Entry {
+                                       deadline: deadline,
+                                       interval: interval,
+                                       callback: callback,
+                                   });
+                self.deadlines.push(Deadline {
+                    at: deadline,
+                    token: token,
+                });
+            }
+            TimerTask::Reschedule { token, deadline } => {
+                if let Some(e) = self.timers.get_mut(&token) {
+                    e.deadline = deadline;
+                    self.deadlines.push(Deadline {
+                        at: deadline,
+                        token: token,
+                    });
+                }
+            }
+            TimerTask::Cancel(token) => {
+                self.timers.remove(&token);
+            }
+        }
+        // timeout may never be reached if tasks keep coming.
+        self.fire_due();
+    }
+
+    fn timeout(&mut self) -> Option<Duration> {
+        self.deadlines.peek().map(|d| {
+            let now = Instant::now();
+            if d.at > now {
+                d.at - now
+            } else {
+                Duration::new(0, 0)
+            }
+        })
+    }
+
+    fn on_timeout(&mut self) {
+        self.fire_due();
+    }
+}
+
+/// A handle to schedule delayed or periodic tasks to other workers.
+#[derive(Clone)]
+pub struct TimerHandle {
+    scheduler: Scheduler<TimerTask>,
+    next_token: Arc<AtomicUsize>,
+}
+
+impl TimerHandle {
+    fn schedule_callback(&self,
+                         delay: Duration,
+                         interval: Option<Duration>,
+                         callback: Callback)
+                         -> Result<TimerToken, ScheduleError<()>> {
+        let token = self.next_token.fetch_add(1, Ordering::SeqCst) as u64;
+        let task = TimerTask::Schedule {
+            token: token,
+            deadline: Instant::now() + delay,
+            interval: interval,
+            callback: callback,
+        };
+        try!(self.scheduler.schedule(task).map_err(|_| ScheduleError::Stopped(())));
+        Ok(TimerToken(token))
+    }
+
+    /// Schedule `task` to the worker of `scheduler` after `delay`.
+    ///
+    /// The task is returned if the timer is stopped, and dropped if it can't
+    /// be scheduled when the timer fires. It stays in the trace of the batch
+    /// being handled by current thread, see `current_trace_id`.
+    pub fn schedule_after<T>(&self,
+                             delay: Duration,
+                             scheduler: Scheduler<T>,
+                             task: T)
+                             -> Result<TimerToken, ScheduleError<T>>
+        where T: Display + Send + 'static
+    {
+        // shared with the callback, so the task can be taken back if the
+        // callback is never scheduled.
+        let slot = Arc::new(Mutex::new(Some(task)));
+        let task = slot.clone();
+        let trace_id = current_trace_id();
+        let callback = Box::new(move || {
+            if let Some(t) = task.lock().unwrap().take() {
+                let res = match trace_id {
+                    Some(id) => scheduler.schedule_traced(t, id),
+                    None => scheduler.schedule(t),
+                };
+                if let Err(e) = res {
+                    debug!("failed to schedule delayed task: {}, drop it", e);
+                }
+            }
+            false
+        });
+        self.schedule_callback(delay, None, callback)
+            .map_err(|_| ScheduleError::Stopped(slot.lock().unwrap().take().unwrap()))
+    }
+
+    /// Schedule a task built by `factory` to the worker of `scheduler` every
+    /// `interval`, until the timer is cancelled or the worker is stopped.
+    ///
+    /// The factory is returned if the timer is stopped.
+    pub fn schedule_repeating<T, F>(&self,
+                                    interval: Duration,
+                                    scheduler: Scheduler<T>,
+                                    factory: F)
+                                    -> Result<TimerToken, ScheduleError<F>>
+        where T: Display + Send + 'static,
+              F: FnMut() -> T + Send + 'static
+    {
+        let slot = Arc::new(Mutex::new(Some(factory)));
+        let factory = slot.clone();
+        let callback = Box::new(move || {
+            let task = match *factory.lock().unwrap() {
+                Some(ref mut f) => f(),
+                None => return false,
+            };
+            match scheduler.schedule(task) {
+                Ok(()) => true,
+                Err(ScheduleError::ClassFull(t)) => {
+                    debug!("too many pending tasks, skip repeating task {}", t);
+                    true
+                }
+                Err(ScheduleError::Stopped(t)) => {
+                    debug!("target worker is stopped, stop repeating task {}", t);
+                    false
+                }
+            }
+        });
+        self.schedule_callback(interval, Some(interval), callback)
+            .map_err(|_| ScheduleError::Stopped(slot.lock().unwrap().take().unwrap()))
+    }
+
+    /// Make the timer fire after `delay` from now instead.
+    ///
+    /// For a repeating timer, following firings keep the original interval.
+    pub fn reschedule(&self,
+                      token: TimerToken,
+                      delay: Duration)
+                      -> Result<(), ScheduleError<()>> {
+        let task = TimerTask::Reschedule {
+            token: token.0,
+            deadline: Instant::now() + delay,
+        };
+        self.scheduler.schedule(task).map_err(|_| ScheduleError::Stopped(()))
+    }
+
+    /// Cancel the timer, it's a no-op if the timer has fired already.
+    pub fn cancel(&self, token: TimerToken) -> Result<(), ScheduleError<()>> {
+        self.scheduler.schedule(TimerTask::Cancel(token.0)).map_err(|_| ScheduleError::Stopped(()))
+    }
+}
+
+/// `TimerWorker` fires the timers of all the `TimerHandle`s it hands out in
+/// a single thread, so workers don't need to sleep or spawn threads to delay
+/// their tasks.
+pub struct TimerWorker {
+    worker: Worker<TimerTask>,
+    next_token: Arc<AtomicUsize>,
+}
+
+impl TimerWorker {
+    pub fn new<S: Into<String>>(name: S) -> TimerWorker {
+        TimerWorker {
+            worker: Worker::new(name),
+            next_token: Arc::new(AtomicUsize::new(1)),
+        }
+    }
+
+    pub fn start(&mut self) -> Result<(), io::Error> {
+        self.worker.start(Runner::new())
+    }
+
+    pub fn handle(&self) -> TimerHandle {
+        TimerHandle {
+            scheduler: self.worker.scheduler(),
+            next_token: self.next_token.clone(),
+        }
+    }
+
+    /// Stop the timer thread, pending timers are dropped without firing.
+    pub fn stop(&mut self) -> Option<JoinHandle<()>> {
+        self.worker.stop()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use super::super::{ScheduleError, test_util};
+
+    #[test]
+    fn test_timer_order() {
+        let mut timer = TimerWorker::new("test-timer");
+        timer.start().unwrap();
+        let handle = timer.handle();
+        let (scheduler, capture) = test_util::capture();
+
+        for delay in &[60, 20, 40] {
+            handle.schedule_after(Duration::from_millis(*delay), scheduler.clone(), *delay)
+                .unwrap();
+        }
+        let timeout = Duration::from_secs(3);
+        assert_eq!(capture.wait_for(3, timeout), vec![20, 40, 60]);
+
+        let mut count = 100;
+        let factory = move || {
+            count += 1;
+            count
+        };
+        let interval = Duration::from_millis(10);
+        let token = handle.schedule_repeating(interval, scheduler.clone(), factory)
+            .unwrap();
+        let fired = capture.wait_for(3, timeout);
+        assert_eq!(&fired[..3], &[101, 102, 103]);
+        handle.cancel(token).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        capture.take_all();
+        thread::sleep(Duration::from_millis(50));
+        capture.assert_empty();
+
+        timer.stop().unwrap().join().unwrap();
+    }
+
+    #[test]
+    fn test_timer_cancel() {
+        let mut timer = TimerWorker::new("test-timer");
+        timer.start().unwrap();
+        let handle = timer.handle();
+        let (scheduler, capture) = test_util::capture();
+
+        let token = handle.schedule_after(Duration::from_millis(30), scheduler.clone(), 1)
+            .unwrap();
+        handle.cancel(token).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        capture.assert_empty();
+        // cancel a fired timer is a no-op.
+        let token = handle.schedule_after(Duration::from_millis(0), scheduler.clone(), 2)
+            .unwrap();
+        assert_eq!(capture.wait_for(1, Duration::from_secs(3)), vec![2]);
+        handle.cancel(token).unwrap();
+
+        let token = handle.schedule_after(Duration::from_secs(60), scheduler.clone(), 3)
+            .unwrap();
+        handle.reschedule(token, Duration::from_millis(10)).unwrap();
+        assert_eq!(capture.wait_for(1, Duration::from_secs(3)), vec![3]);
+
+        timer.stop().unwrap().join().unwrap();
+    }
+
+    #[test]
+    fn test_timer_target_stopped() {
+        let mut timer = TimerWorker::new("test-timer");
+        timer.start().unwrap();
+        let handle = timer.handle();
+        let (stopped, mut stopped_capture) = test_util::capture::<u64>();
+        stopped_capture.stop();
+        let (scheduler, capture) = test_util::capture();
+
+        handle.schedule_after(Duration::from_millis(10), stopped.clone(), 1).unwrap();
+        handle.schedule_repeating(Duration::from_millis(10), stopped.clone(), || 2)
+            .unwrap();
+        handle.schedule_after(Duration::from_millis(30), scheduler.clone(), 3).unwrap();
+        assert_eq!(capture.wait_for(1, Duration::from_secs(3)), vec![3]);
+        stopped_capture.assert_empty();
+
+        timer.stop().unwrap().join().unwrap();
+        // the task is returned if the timer is stopped.
+        match handle.schedule_after(Duration::from_millis(10), scheduler.clone(), 4) {
+            Err(ScheduleError::Stopped(4)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        match handle.schedule_repeating(Duration::from_millis(10), scheduler, || 5) {
+            Err(ScheduleError::Stopped(mut f)) => assert_eq!(f(), 5),
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+}